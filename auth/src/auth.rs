@@ -28,8 +28,7 @@ impl ApiInfo {
 	}
 }
 
-use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
-use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 
 use log::error;
 use std::env;
@@ -47,21 +46,12 @@ pub async fn validator(
 	req: ServiceRequest,
 	credentials: BearerAuth,
 ) -> Result<ServiceRequest, actix_web::Error> {
-	let config = req
-		.app_data::<Config>()
-		.map(|data| data.clone())
-		.unwrap_or_else(Default::default);
 	match validate_token(credentials.token()).await {
-		Ok(res) => {
-			if res {
-				Ok(req)
-			} else {
-				Err(AuthenticationError::from(config).into())
-			}
-		}
+		Ok(true) => Ok(req),
+		Ok(false) => Err(ApplicationError::Unauthorized("Invalid access token.".to_owned()).into()),
 		Err(e) => {
 			error!("{:?}", e);
-			Err(AuthenticationError::from(config).into())
+			Err(e.into())
 		}
 	}
 }
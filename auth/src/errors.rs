@@ -1,8 +1,9 @@
 use actix_web::{
     error,
-    http::{header::ContentType, StatusCode},
+    http::{header, StatusCode},
     HttpResponse,
 };
+use serde::Serialize;
 
 use log::error;
 
@@ -12,7 +13,9 @@ pub enum ApplicationError {
     DB(tokio_postgres::Error),
     InputCheck(String),
     Message(String),
-    MessageAndStatus(String, u16),
+    NotFound(String),
+    Unauthorized(String),
+    MethodNotAllowed,
     JWKSFetchError,
 }
 
@@ -34,33 +37,84 @@ impl std::fmt::Display for ApplicationError {
     }
 }
 
+impl ApplicationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ApplicationError::InputCheck(_) => ErrorCode::InvalidRequest,
+            ApplicationError::NotFound(_) => ErrorCode::NotFound,
+            ApplicationError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ApplicationError::MethodNotAllowed => ErrorCode::MethodNotAllowed,
+            ApplicationError::DBPool(_) | ApplicationError::JWKSFetchError => {
+                ErrorCode::ServiceUnavailable
+            }
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// The stable codes of the errors told to clients, which are the same as the SFU's.
+///
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is malformed.
+    InvalidRequest,
+    /// The access token is missing or invalid.
+    Unauthorized,
+    NotFound,
+    MethodNotAllowed,
+    /// A dependency such as the database is down, so the client should retry later.
+    ServiceUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The body of error responses.
+///
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
 impl error::ResponseError for ApplicationError {
     fn error_response(&self) -> HttpResponse {
         error!("{:?}", self);
 
         let message = match self {
             ApplicationError::InputCheck(m) => m,
-            ApplicationError::MessageAndStatus(m, _) => m,
+            ApplicationError::NotFound(m) => m,
+            ApplicationError::Unauthorized(m) => m,
+            ApplicationError::MethodNotAllowed => "Method not allowed.",
+            ApplicationError::DBPool(_) | ApplicationError::JWKSFetchError => {
+                "The service is currently unavailable."
+            }
             _ => "Internal Server Error",
         };
 
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .body(format!(
-                "{{
-                \"message\": \"{}\"
-            }}",
-                message
-            ))
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApplicationError::Unauthorized(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody {
+            code: self.code(),
+            message: message.to_owned(),
+        })
     }
 
     fn status_code(&self) -> StatusCode {
-        match self {
-            ApplicationError::InputCheck(_) => StatusCode::BAD_REQUEST,
-            ApplicationError::MessageAndStatus(_, s) => {
-                StatusCode::from_u16(*s).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        }
+        self.code().status()
     }
 }
@@ -1,4 +1,4 @@
-use actix_web::{web, Responder};
+use actix_web::{web, HttpResponse, Responder};
 
 use serde::{Deserialize, Serialize};
 use std::convert::From;
@@ -127,10 +127,7 @@ async fn member_delegate(
         Ok(room) => room,
         Err(e) => {
             error!("{:?}", e);
-            return Err(ApplicationError::MessageAndStatus(
-                "Room doesn't exist.".to_owned(),
-                404,
-            ));
+            return Err(ApplicationError::NotFound("Room doesn't exist.".to_owned()));
         }
     };
 
@@ -203,3 +200,18 @@ fn check_length(name: &str, field_name: &str, max: usize) -> Result<(), Applicat
     };
     Err(ApplicationError::InputCheck(error_message))
 }
+
+pub async fn not_found() -> Result<HttpResponse, ApplicationError> {
+    Err(ApplicationError::NotFound("Not found.".to_owned()))
+}
+
+pub async fn method_not_allowed() -> Result<HttpResponse, ApplicationError> {
+    Err(ApplicationError::MethodNotAllowed)
+}
+
+/// Rejects request bodies that can't be parsed with an 'invalid_request' error.
+///
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|e, _| ApplicationError::InputCheck(e.to_string()).into())
+}
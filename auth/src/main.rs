@@ -1,4 +1,4 @@
-use actix_web::{web, App, HttpServer, Responder};
+use actix_web::{web, App, HttpServer, Resource, Responder, Route};
 
 use mobc::Pool;
 use mobc_postgres::PgConnectionManager;
@@ -35,24 +35,26 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         let auth = HttpAuthentication::bearer(auth::validator);
         App::new()
+            .app_data(handler::json_config())
             .service(
                 web::scope("/auth")
                     .service(
                         web::scope("api-info")
-                            .route("", web::get().to(api_info))
+                            .service(only(web::get().to(api_info)))
                     )
                     .service(
                         web::scope("/room")
                             .wrap(auth)
                             .app_data(web::Data::new(pool.clone()))
-                            .route("", web::post().to(handler::room)),
+                            .service(only(web::post().to(handler::room))),
                     )
                     .service(
                         web::scope("/member")
                             .app_data(web::Data::new(pool.clone()))
-                            .route("", web::post().to(handler::member)),
+                            .service(only(web::post().to(handler::member))),
                     )               
             )
+            .default_service(web::to(handler::not_found))
 
     })
     .bind(("0.0.0.0", port))?
@@ -60,6 +62,15 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
+/// The resource at the root of a scope serving only the route,
+/// which answers other methods with a 'method_not_allowed' error.
+///
+fn only(route: Route) -> Resource {
+    web::resource("")
+        .route(route)
+        .default_service(web::to(handler::method_not_allowed))
+}
+
 async fn api_info() -> impl Responder {
     web::Json(auth::ApiInfo::new())
 }
\ No newline at end of file
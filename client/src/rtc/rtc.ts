@@ -1,34 +1,42 @@
 import { reactive } from 'vue';
 import { Member, VideoWindow, VideoModel, MeetingRoomData, MeetingRoomModelHandleHolder } from '../app-data-types';
 import { backToHomeWithDelay, handleUnrecoverableError } from '../system';
+import {
+	ClientMessage, MediaState, PROTOCOL_VERSION, RosterEntry, ServerMessage, SessionMessage, SlotEntry,
+	credentialSubprotocols
+} from './signaling';
 
 const SECRET_HEADER_KEY = 'X-W-Chat-Secret';
 
 const TRACK_ID_PREF = 'sfu-stream-';
 const DATA_CHANNEL_LABEL_PREF = 'sfu-data-ch-';
 
-enum SubscriberMessageType {
-	Offer = 'Offer',
-	Answer = 'Answer',
-	Start = 'Start',
-	Prepare = 'Prepare',
-	IceCandidate = 'IceCandidate',
-	Ping = 'Ping',
-	Pong = 'Pong',
-}
-
-interface SubscriberMessage {
-	msg_type: SubscriberMessageType,
-	message: string
+interface PeerTracks {
+	audio?: MediaStreamTrack,
+	video?: MediaStreamTrack
 }
 
 const VIDEO_HEIGHT_RATIO = 0.25;
 const PING_INTERVAL_MILLIS = 3000;
-const WAIT_BEFORE_REMOVAL_MILLIS = 5000;
+const RESUME_RETRY_INTERVAL_MILLIS = 2000;
+// The SFU closes with a code in this range when it evicts the peer, which can't be resumed.
+const MIN_APP_CLOSE_CODE = 4000;
+const SESSION_TAKEN_OVER_CLOSE_CODE = 4005;
+const DUPLICATE_SESSION_CLOSE_CODE = 4006;
+const UNAUTHENTICATED_CLOSE_CODE = 4008;
+const RATE_LIMITED_CLOSE_CODE = 4009;
+const SLOW_CONSUMER_CLOSE_CODE = 4010;
+const TRY_AGAIN_LATER_CLOSE_CODE = 1013;
 
 class ConnectionHandler {
 	
 	private socket: WebSocket | undefined;
+	private session: SessionMessage | undefined;
+	private readonly roster: Map<string, RosterEntry> = new Map();
+	private readonly receivedTracks: Map<string, MediaStreamTrack> = new Map();
+	private readonly displayedTracks: Map<string, PeerTracks> = new Map();
+	private slots: SlotEntry[] = [];
+	private localTracks: PeerTracks = {};
 	private readonly globalResizeEvents: Array<(event: UIEvent) => void> = [];
 	
 	constructor() {
@@ -52,13 +60,13 @@ class ConnectionHandler {
 
 		const isHttps = location.protocol.startsWith('https:');
 		const scheme = isHttps ? 'wss:' : 'ws:';
-		this.socket = new WebSocket(`${scheme}//${location.host}/ws-app/subscribe/${member.tokenToSend}`);
+		const wsBaseUrl = `${scheme}//${location.host}/ws-app`;
 
 		pc.addEventListener('icecandidate', (event: RTCPeerConnectionIceEvent) => {
-			this.sendMessage(JSON.stringify({
-				msg_type: SubscriberMessageType.IceCandidate,
-				message: JSON.stringify(event.candidate)
-			}));
+			this.sendMessage({
+				type: 'ice_candidate',
+				payload: event.candidate ? event.candidate.toJSON() : null
+			});
 		});
 		pc.addEventListener('datachannel', (event: RTCDataChannelEvent) => {
 
@@ -70,37 +78,21 @@ class ConnectionHandler {
 		});
 
 		const sendPing = () => {
-			const msg: SubscriberMessage = {
-				msg_type: SubscriberMessageType.Ping,
-				message: ''
-			};
-			this.sendMessage(JSON.stringify(msg));
+			this.sendMessage({ type: 'ping' });
 			setTimeout(sendPing, PING_INTERVAL_MILLIS);
 		};
-		this.socket.addEventListener('open', () => {
-			const msg: SubscriberMessage = {
-				msg_type: SubscriberMessageType.Prepare,
-				message: ''
-			};
-			this.sendMessage(JSON.stringify(msg));
-			setTimeout(sendPing, PING_INTERVAL_MILLIS);
-		});
-		this.socket.addEventListener('error', handleUnrecoverableError);
-		this.socket.addEventListener('close', handleUnrecoverableError);
+		const handleMessage = async (event: MessageEvent) => {
 
-		this.socket.addEventListener('message', async (event: MessageEvent) => {
+			const message = JSON.parse(event.data) as ServerMessage;
 
-			const message = JSON.parse(event.data) as SubscriberMessage;
-
-			switch (message.msg_type) {
-			case SubscriberMessageType.Offer: {
-
-				if (!message.message) {
-					console.error('Invalid message format', message);
-					break;
-				}
+			switch (message.type) {
+			case 'hello': {
+				console.info('Signaling protocol version', message.payload.protocol_version);
+				break;
+			}
+			case 'offer': {
 
-				const offer = JSON.parse(message.message);
+				const offer = message.payload;
 				console.debug('---------------------- offer -----------------------------');
 				console.debug(offer.sdp);
 				console.debug('---------------------- offer -----------------------------');
@@ -124,25 +116,121 @@ class ConnectionHandler {
 				console.debug(answer.sdp);
 				console.debug('---------------------- answer -----------------------------');
 
-				this.sendMessage(JSON.stringify({
-					msg_type: SubscriberMessageType.Answer,
-					message: JSON.stringify(answer)
-				}));
+				this.sendMessage({
+					type: 'answer',
+					payload: { type: 'answer', sdp: answer.sdp }
+				});
 				break;
 			}
-			case SubscriberMessageType.IceCandidate: {
-				const iceCandidate = JSON.parse(message.message);
+			case 'ice_candidate': {
+				const iceCandidate = message.payload;
 				console.debug('Receive ICE candidate: ', iceCandidate);
-				pc.addIceCandidate(iceCandidate);
+				pc.addIceCandidate({ ...iceCandidate, candidate: iceCandidate.candidate ?? undefined });
 				break;
 			}
-			case SubscriberMessageType.Pong: {
+			case 'pong': {
 				console.debug('Receive Pong message.');
 				break;
 			}
+			case 'roster': {
+				message.payload.members.forEach(entry => this.putRosterEntry(entry, modelHandleHolder));
+				break;
+			}
+			case 'peer_joined':
+			case 'peer_updated': {
+				this.putRosterEntry(message.payload, modelHandleHolder);
+				break;
+			}
+			case 'peer_left': {
+				this.roster.delete(message.payload.peer_id);
+				break;
+			}
+			case 'session': {
+				this.session = message.payload;
+				break;
+			}
+			case 'slots': {
+				this.slots = message.payload.slots;
+				this.applySlots(data, member, modelHandleHolder);
+				break;
+			}
+			case 'forwarding': {
+				for (const track of message.payload.undecodable) {
+					console.warn(`The ${track.kind} of ${track.peer_id} is in a codec this browser can't decode.`);
+				}
+				break;
+			}
+			case 'error': {
+				console.error('Signaling error', message.payload.code, message.payload.message);
+				break;
+			}
 			default:
 				break;
 			}
+		};
+
+		//
+		// When the socket is lost, e.g. on switching networks, it is reconnected with the resume token
+		// within the grace period. The SFU then restarts ICE on the same RTCPeerConnection.
+		//
+		let resumeDeadline = 0;
+		const connect = (url: string, protocols: string[], onOpen: () => void) => {
+			const socket = new WebSocket(url, protocols);
+			socket.addEventListener('open', onOpen);
+			socket.addEventListener('error', event => console.error('WebSocket error', event));
+			socket.addEventListener('close', onClose);
+			socket.addEventListener('message', handleMessage);
+			this.socket = socket;
+		};
+		const resume = () => {
+			if (!this.session || resumeDeadline < Date.now()) {
+				handleUnrecoverableError();
+				return;
+			}
+			const protocols = credentialSubprotocols(member.tokenToSend, this.session.resume_token);
+			connect(`${wsBaseUrl}/resume`, protocols, () => {
+				console.info('The session has been resumed.');
+				resumeDeadline = 0;
+				// The tracks may have been muted or unmuted while the socket was lost.
+				this.sendMediaState();
+			});
+		};
+		const onClose = (event: CloseEvent) => {
+			if (event.code === SESSION_TAKEN_OVER_CLOSE_CODE) {
+				backToHomeWithDelay('This meeting has been opened in another tab or device.');
+				return;
+			}
+			if (event.code === DUPLICATE_SESSION_CLOSE_CODE) {
+				backToHomeWithDelay('You have already joined this meeting in another tab or device.');
+				return;
+			}
+			if (event.code === UNAUTHENTICATED_CLOSE_CODE) {
+				backToHomeWithDelay('Your membership of this meeting is no longer valid. Please join it again.');
+				return;
+			}
+			if (event.code === RATE_LIMITED_CLOSE_CODE || event.code === TRY_AGAIN_LATER_CLOSE_CODE) {
+				backToHomeWithDelay('The service is currently busy. Please retry later.');
+				return;
+			}
+			if (event.code === SLOW_CONSUMER_CLOSE_CODE) {
+				backToHomeWithDelay('Your connection is too slow to keep up with this meeting.');
+				return;
+			}
+			if (!this.session || event.code >= MIN_APP_CLOSE_CODE) {
+				handleUnrecoverableError();
+				return;
+			}
+			if (resumeDeadline === 0) {
+				resumeDeadline = Date.now() + this.session.resume_grace_period_secs * 1000;
+			}
+			setTimeout(resume, RESUME_RETRY_INTERVAL_MILLIS);
+		};
+
+		const protocols = credentialSubprotocols(member.tokenToSend);
+		connect(`${wsBaseUrl}/subscribe?protocol_version=${PROTOCOL_VERSION}`, protocols, () => {
+			this.sendMessage({ type: 'prepare' });
+			this.sendMediaState();
+			setTimeout(sendPing, PING_INTERVAL_MILLIS);
 		});
 	}
 
@@ -154,83 +242,13 @@ class ConnectionHandler {
 		const pc = await this.newRTCPeerConnection(member);
 
 		pc.ontrack = (event: RTCTrackEvent) => {
-			const mediaStream = event.streams[0];
-			if (!mediaStream) {
+			const mid = event.transceiver.mid;
+			console.debug('on_track', mid, event.track);
+			if (!mid) {
 				return;
 			}
-			const videoId = mediaStream.id;
-
-			console.debug('on_track', event.track);
-
-			if (videoId.startsWith(TRACK_ID_PREF) && event.track.kind === 'video') {
-
-				// https://stackoverflow.com/questions/34990672/control-volume-gain-for-video-audio-stream-in-firefox
-				const audioTrack = mediaStream.getAudioTracks()[0];
-				const videoTrack = mediaStream.getVideoTracks()[0];
-				if (!audioTrack || !videoTrack) {
-					return;
-				}
-				const audio = new Audio();
-				audio.srcObject = new MediaStream([ audioTrack ]);
-				audio.onloadedmetadata = () => {
-					audio.play();
-				};
-
-				const videoStream  = new MediaStream([ videoTrack ]);
-
-				const videoWindow: VideoWindow = reactive({
-					id: videoId,
-					name: '',
-					srcObject: videoStream,
-					isDisplayed: false,
-					cssHeight: window.innerHeight * VIDEO_HEIGHT_RATIO
-				});
-
-				fetchMemberName(videoId.replace(TRACK_ID_PREF, ''), member.tokenToSend)
-					.then(({ name }: { name: string }) => {
-						videoWindow.name = name;
-					});
-
-				this.globalResizeEvents.push(() => {
-					videoWindow.cssHeight = window.innerHeight * VIDEO_HEIGHT_RATIO; 
-				});
-
-				const videoModel: VideoModel = {
-					videoWindow,
-					audio
-				};
-
-				modelHandleHolder.putVideo(videoId, videoModel);
-				modelHandleHolder.mute(videoId);
-
-				data.videos.push(videoWindow);
-
-				let removeTimer;
-				event.track.onunmute = () => {
-					console.debug(`unmute ${videoId}`);
-					modelHandleHolder.play(videoId);
-					clearTimeout(removeTimer);
-				};
-				event.track.onmute = () => {
-					modelHandleHolder.leave(videoId);
-
-					removeTimer = setTimeout(() => {
-						console.debug(`mute ${videoId}`);
-						for (let i = 0; data.videos.length; i++) {
-							const video = data.videos[i];
-							if (!video) {
-								continue;
-							}
-							if (videoId === video.id) {
-								console.debug(`Remove the video whose index is ${i}`);
-								data.videos.splice(i, 1);
-								break;
-							}
-						}
-						modelHandleHolder.delete(videoId);
-					}, WAIT_BEFORE_REMOVAL_MILLIS);
-				};
-			}
+			this.receivedTracks.set(mid, event.track);
+			this.applySlots(data, member, modelHandleHolder);
 		};
 		const stream = await navigator.mediaDevices.getUserMedia({
 			video: true,
@@ -251,6 +269,13 @@ class ConnectionHandler {
 		myVideo.onmute = () => console.debug('My video muted.');
 		myVideo.onunmute = () => console.debug('My video unmuted.');
 
+		// The other peers are told when the source of a track stops or resumes producing media.
+		this.localTracks = { audio: stream.getAudioTracks()[0], video: myVideo };
+		[this.localTracks.audio, this.localTracks.video].forEach(track => {
+			track?.addEventListener('mute', () => this.sendMediaState());
+			track?.addEventListener('unmute', () => this.sendMediaState());
+		});
+
 		data.srcObject = new MediaStream( [ myVideo ]);
 		
 
@@ -293,12 +318,149 @@ class ConnectionHandler {
 		return new RTCPeerConnection(config);
 	}
 
-	private sendMessage(text: string): void {
+	/**
+	 * Shows the videos of the publishers occupying the slots and removes those who have left them.
+	 */
+	private applySlots(data: MeetingRoomData, member: Member, modelHandleHolder: MeetingRoomModelHandleHolder): void {
+		const tracksByPeer: Map<string, PeerTracks> = new Map();
+		this.slots.forEach(slot => {
+			const track = this.receivedTracks.get(slot.mid);
+			if (!slot.peer_id || !slot.kind || !track) {
+				return;
+			}
+			const tracks = tracksByPeer.get(slot.peer_id) || {};
+			tracks[slot.kind] = track;
+			tracksByPeer.set(slot.peer_id, tracks);
+		});
+
+		this.displayedTracks.forEach((tracks, peerId) => {
+			const current = tracksByPeer.get(peerId);
+			if (!current || current.audio !== tracks.audio || current.video !== tracks.video) {
+				this.removeVideo(peerId, data, modelHandleHolder);
+			}
+		});
+		tracksByPeer.forEach((tracks, peerId) => {
+			if (tracks.audio && tracks.video && !this.displayedTracks.has(peerId)) {
+				this.addVideo(peerId, tracks.audio, tracks.video, data, member, modelHandleHolder);
+			}
+		});
+	}
+
+	private addVideo(
+		peerId: string,
+		audioTrack: MediaStreamTrack,
+		videoTrack: MediaStreamTrack,
+		data: MeetingRoomData,
+		member: Member,
+		modelHandleHolder: MeetingRoomModelHandleHolder): void {
+
+		const videoId = `${TRACK_ID_PREF}${peerId}`;
+
+		// https://stackoverflow.com/questions/34990672/control-volume-gain-for-video-audio-stream-in-firefox
+		const audio = new Audio();
+		audio.srcObject = new MediaStream([ audioTrack ]);
+		audio.onloadedmetadata = () => {
+			audio.play();
+		};
+
+		const videoStream  = new MediaStream([ videoTrack ]);
+
+		const videoWindow: VideoWindow = reactive({
+			id: videoId,
+			name: '',
+			srcObject: videoStream,
+			isDisplayed: false,
+			cssHeight: window.innerHeight * VIDEO_HEIGHT_RATIO
+		});
+
+		const rosterEntry = this.roster.get(peerId);
+		if (rosterEntry) {
+			videoWindow.name = rosterEntry.member_name;
+		} else {
+			fetchMemberName(peerId, member.tokenToSend)
+				.then(({ name }: { name: string }) => {
+					videoWindow.name = name;
+				});
+		}
+
+		this.globalResizeEvents.push(() => {
+			videoWindow.cssHeight = window.innerHeight * VIDEO_HEIGHT_RATIO; 
+		});
+
+		const videoModel: VideoModel = {
+			videoWindow,
+			audio
+		};
+
+		modelHandleHolder.putVideo(videoId, videoModel);
+		modelHandleHolder.mute(videoId);
+
+		data.videos.push(videoWindow);
+		this.displayedTracks.set(peerId, { audio: audioTrack, video: videoTrack });
+
+		// The track of a reused slot may already be receiving the new publisher's media.
+		if (!videoTrack.muted) {
+			modelHandleHolder.play(videoId);
+		}
+		videoTrack.onunmute = () => {
+			console.debug(`unmute ${videoId}`);
+			modelHandleHolder.play(videoId);
+		};
+		videoTrack.onmute = () => {
+			console.debug(`mute ${videoId}`);
+			modelHandleHolder.leave(videoId);
+		};
+	}
+
+	private removeVideo(peerId: string, data: MeetingRoomData, modelHandleHolder: MeetingRoomModelHandleHolder): void {
+		const videoId = `${TRACK_ID_PREF}${peerId}`;
+		const tracks = this.displayedTracks.get(peerId);
+		if (tracks && tracks.video) {
+			tracks.video.onunmute = null;
+			tracks.video.onmute = null;
+		}
+		this.displayedTracks.delete(peerId);
+
+		const videoModel = modelHandleHolder.getVideo(videoId);
+		if (videoModel) {
+			videoModel.audio.pause();
+		}
+
+		const index = data.videos.findIndex(video => video.id === videoId);
+		if (index >= 0) {
+			console.debug(`Remove the video whose index is ${index}`);
+			data.videos.splice(index, 1);
+		}
+		modelHandleHolder.delete(videoId);
+	}
+
+	private putRosterEntry(entry: RosterEntry, modelHandleHolder: MeetingRoomModelHandleHolder): void {
+		this.roster.set(entry.peer_id, entry);
+		const video = modelHandleHolder.getVideo(`${TRACK_ID_PREF}${entry.peer_id}`);
+		if (video) {
+			video.videoWindow.name = entry.member_name;
+		}
+	}
+
+	private sendMediaState(): void {
+		const isMuted = (track?: MediaStreamTrack) => !track || track.muted || !track.enabled;
+		const mediaState: MediaState = {
+			audio_muted: isMuted(this.localTracks.audio),
+			video_muted: isMuted(this.localTracks.video)
+		};
+		this.sendMessage({ type: 'media_state', payload: mediaState });
+	}
+
+	private sendMessage(message: ClientMessage): void {
 		if (!this.socket) {
 			console.error('Socket is null');
 			return;
 		}
-		this.socket.send(text);
+		if (this.socket.readyState !== WebSocket.OPEN) {
+			console.debug('Socket is not open so the message is dropped.', message);
+			return;
+		}
+		this.socket.send(JSON.stringify(message));
 	}
 }
 
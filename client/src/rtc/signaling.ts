@@ -0,0 +1,119 @@
+// The signaling messages exchanged with the SFU.
+// Keep in sync with the SFU's JSON Schema, printed by `npm run signaling-schema`.
+
+export const PROTOCOL_VERSION = 2;
+
+// The credentials are passed in Sec-WebSocket-Protocol, not in the URL, so that they stay out of access logs.
+// The SFU selects SIGNALING_SUBPROTOCOL in return.
+export const SIGNALING_SUBPROTOCOL = 'waku-signaling';
+
+export function credentialSubprotocols(token: string, resumeToken?: string): string[] {
+	// '=' isn't allowed in subprotocols, and the SFU decodes the token without padding.
+	const protocols = [SIGNALING_SUBPROTOCOL, `token.${token.replace(/=+$/, '')}`];
+	if (resumeToken) {
+		protocols.push(`resume.${resumeToken}`);
+	}
+	return protocols;
+}
+
+export type MediaKind = 'audio' | 'video';
+
+export interface SessionDescription {
+	type: 'offer' | 'pranswer' | 'answer' | 'rollback',
+	sdp: string
+}
+
+export interface IceCandidate {
+	candidate?: string | null,
+	sdpMid?: string | null,
+	sdpMLineIndex?: number | null,
+	usernameFragment?: string | null
+}
+
+export interface MediaState {
+	audio_muted: boolean,
+	video_muted: boolean
+}
+
+export interface RosterEntry extends MediaState {
+	peer_id: string,
+	member_id: number,
+	member_name: string,
+	role: 'member',
+	published_kinds: MediaKind[]
+}
+
+export interface SessionMessage {
+	peer_id: string,
+	resume_token: string,
+	resume_grace_period_secs: number
+}
+
+// The SFU reuses its senders for different publishers, so tracks are matched to publishers by mid.
+// 'undecodable' lists the tracks the SFU leaves out since the browser can't decode their codecs.
+export interface PausedTrack {
+	peer_id: string,
+	kind: MediaKind | null
+}
+
+export interface SlotEntry {
+	mid: string,
+	kind: MediaKind | null,
+	peer_id: string | null
+}
+
+export interface SubscriptionMessage {
+	peer_ids?: string[] | null,
+	kinds?: MediaKind[] | null
+}
+
+// The same codes are used in the error responses of the SFU and the auth service.
+export type ErrorCode =
+	| 'invalid_request'
+	| 'unauthorized'
+	| 'forbidden'
+	| 'not_found'
+	| 'method_not_allowed'
+	| 'rate_limited'
+	| 'service_unavailable'
+	| 'invalid_message'
+	| 'negotiation_failed'
+	| 'unsupported_protocol_version'
+	| 'internal';
+
+export interface ErrorBody {
+	code: ErrorCode,
+	message: string
+}
+
+export type ClientMessage =
+	| { type: 'authenticate', payload: { token: string, resume_token?: string | null } }
+	| { type: 'prepare' }
+	| { type: 'offer', payload: SessionDescription }
+	| { type: 'answer', payload: SessionDescription }
+	| { type: 'ice_candidate', payload: IceCandidate | null }
+	| { type: 'ping' }
+	| { type: 'pong' }
+	| { type: 'pin', payload: { peer_ids: string[] } }
+	| { type: 'subscribe', payload: SubscriptionMessage }
+	| { type: 'unsubscribe', payload: SubscriptionMessage }
+	| { type: 'media_state', payload: MediaState }
+	| { type: 'restart_ice' };
+
+export type ServerMessage =
+	| { type: 'hello', payload: { protocol_version: number } }
+	| { type: 'offer', payload: SessionDescription }
+	| { type: 'answer', payload: SessionDescription }
+	| { type: 'ice_candidate', payload: IceCandidate }
+	| { type: 'pong' }
+	| { type: 'active_speaker', payload: { peer_id: string | null } }
+	| { type: 'forwarding', payload: { paused: PausedTrack[], undecodable: PausedTrack[] } }
+	| { type: 'slots', payload: { slots: SlotEntry[] } }
+	| { type: 'roster', payload: { peer_id: string, members: RosterEntry[] } }
+	| { type: 'peer_joined', payload: RosterEntry }
+	| { type: 'peer_updated', payload: RosterEntry }
+	| { type: 'peer_left', payload: { peer_id: string } }
+	| { type: 'session', payload: SessionMessage }
+	| { type: 'resync' }
+	| { type: 'evicted', payload: { code: number, reason: string } }
+	| { type: 'error', payload: ErrorBody };
@@ -18,10 +18,20 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$APP_DATABASE_NAME
     CREATE TABLE IF NOT EXISTS ${APP_SCHEMA}.rooms (
         room_id bigserial PRIMARY KEY,
         room_name varchar(30) NOT NULL,
-        secret_token varchar(120) NOT NULL
+        secret_token varchar(120) NOT NULL,
+        last_n integer,
+        duplicate_session_policy varchar(10),
+        video_codecs varchar(100),
+        audio_codecs varchar(100)
     );
     ALTER TABLE ${APP_SCHEMA}.rooms OWNER TO ${APP_USER};
 
+    -- Columns added after the table was first created.
+    ALTER TABLE ${APP_SCHEMA}.rooms ADD COLUMN IF NOT EXISTS last_n integer;
+    ALTER TABLE ${APP_SCHEMA}.rooms ADD COLUMN IF NOT EXISTS duplicate_session_policy varchar(10);
+    ALTER TABLE ${APP_SCHEMA}.rooms ADD COLUMN IF NOT EXISTS video_codecs varchar(100);
+    ALTER TABLE ${APP_SCHEMA}.rooms ADD COLUMN IF NOT EXISTS audio_codecs varchar(100);
+
     CREATE TABLE IF NOT EXISTS ${APP_SCHEMA}.members (
         member_id bigserial PRIMARY KEY,
         room_id bigint REFERENCES myappsch.rooms (room_id) ON DELETE CASCADE NOT NULL,
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use warp::http::HeaderMap;
+use warp::{reject, Filter, Rejection};
+
+use log::warn;
+
+use crate::config;
+
+/// The WebSocket close code and reason told to a peer whose member opens sessions too often.
+pub const RATE_LIMITED: (u16, &str) = (4009, "rate_limited");
+
+/// The number of buckets a rate limiter holds before it forgets the full ones.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Rejects a request from an origin not in 'ALLOWED_ORIGINS'.
+#[derive(Debug)]
+pub struct OriginNotAllowed;
+
+impl reject::Reject for OriginNotAllowed {}
+
+/// Rejects a request from a client IP that has exceeded its rate limit.
+#[derive(Debug)]
+pub struct TooManyRequests;
+
+impl reject::Reject for TooManyRequests {}
+
+/// Which origins and how often clients may access the SFU.
+///
+pub struct AccessPolicy {
+    /// None allows any origin.
+    allowed_origins: Option<Vec<String>>,
+    /// The header a reverse proxy puts the client IP in, otherwise the peer address is used.
+    client_ip_header: Option<String>,
+    connections_per_ip: RateLimiter<IpAddr>,
+    connections_per_member: RateLimiter<i64>,
+    messages_per_member: RateLimiter<i64>,
+}
+
+pub type AccessPolicyRef = Arc<AccessPolicy>;
+
+impl AccessPolicy {
+    pub fn from_env() -> Self {
+        let allowed_origins: String = config::env_or("ALLOWED_ORIGINS", "".to_owned());
+        let allowed_origins: Vec<String> = allowed_origins
+            .split(',')
+            .map(|origin| origin.trim().to_owned())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        let client_ip_header: String = config::env_or("CLIENT_IP_HEADER", "".to_owned());
+        let minute = Duration::from_secs(60);
+
+        AccessPolicy {
+            allowed_origins: Some(allowed_origins).filter(|origins| !origins.is_empty()),
+            client_ip_header: Some(client_ip_header).filter(|header| !header.is_empty()),
+            connections_per_ip: RateLimiter::new(
+                config::env_or("CONNECTIONS_PER_IP_PER_MIN", 30),
+                minute,
+            ),
+            connections_per_member: RateLimiter::new(
+                config::env_or("CONNECTIONS_PER_MEMBER_PER_MIN", 20),
+                minute,
+            ),
+            messages_per_member: RateLimiter::new(
+                config::env_or("MESSAGES_PER_MEMBER_PER_SEC", 50),
+                Duration::from_secs(1),
+            ),
+        }
+    }
+
+    /// Requests without 'Origin' are allowed since browsers always send it on cross-origin ones.
+    ///
+    pub fn is_allowed_origin(&self, origin: Option<&str>) -> bool {
+        match (&self.allowed_origins, origin) {
+            (Some(allowed_origins), Some(origin)) => allowed_origins.iter().any(|o| o == origin),
+            _ => true,
+        }
+    }
+
+    /// The CORS policy of the REST endpoints, which also rejects disallowed origins.
+    ///
+    pub fn cors(&self, allowed_headers: &[&str]) -> warp::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(vec!["GET"])
+            .allow_headers(allowed_headers.to_vec());
+        match &self.allowed_origins {
+            Some(allowed_origins) => cors.allow_origins(allowed_origins.iter().map(String::as_str)),
+            None => cors.allow_any_origin(),
+        }
+    }
+
+    pub fn allows_connection_of_member(&self, member_id: i64) -> bool {
+        self.connections_per_member.try_acquire(member_id)
+    }
+
+    pub fn allows_message_of_member(&self, member_id: i64) -> bool {
+        self.messages_per_member.try_acquire(member_id)
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, remote: Option<SocketAddr>) -> Option<IpAddr> {
+        match &self.client_ip_header {
+            Some(header) => headers
+                .get(header.as_str())
+                .and_then(|value| value.to_str().ok())
+                // 'X-Forwarded-For' lists the client first.
+                .and_then(|value| value.split(',').next())
+                .and_then(|ip| ip.trim().parse().ok()),
+            None => remote.map(|remote| remote.ip()),
+        }
+    }
+}
+
+/// Rejects WebSocket upgrades from origins not allowed.
+///
+pub fn check_origin(
+    policy: AccessPolicyRef,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and_then(move |origin: Option<String>| {
+            let allowed = policy.is_allowed_origin(origin.as_deref());
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    warn!("Reject the request from the origin {:?}.", origin);
+                    Err(reject::custom(OriginNotAllowed))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Rejects WebSocket upgrades from client IPs connecting too often.
+///
+pub fn limit_connections_per_ip(
+    policy: AccessPolicyRef,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::addr::remote())
+        .and_then(move |headers: HeaderMap, remote: Option<SocketAddr>| {
+            let allowed = match policy.client_ip(&headers, remote) {
+                Some(ip) => policy.connections_per_ip.try_acquire(ip),
+                None => true,
+            };
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    warn!(
+                        "Reject the connection from {:?} exceeding the rate limit.",
+                        remote
+                    );
+                    Err(reject::custom(TooManyRequests))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// A token bucket for each key, holding up to 'count' tokens and refilled at 'count' per 'period'.
+///
+/// A count of zero disables the limit.
+///
+pub struct RateLimiter<K> {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<K, (f64, Instant)>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(count: u32, period: Duration) -> Self {
+        RateLimiter {
+            capacity: count as f64,
+            refill_per_sec: count as f64 / period.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token of the key and returns whether there has been one.
+    ///
+    pub fn try_acquire(&self, key: K) -> bool {
+        if self.capacity == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Full buckets are the same as absent ones.
+            buckets.retain(|_, (tokens, at)| self.refill(*tokens, *at, now) < self.capacity);
+        }
+
+        let (tokens, at) = buckets.entry(key).or_insert((self.capacity, now));
+        *tokens = self.refill(*tokens, *at, now);
+        *at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, tokens: f64, at: Instant, now: Instant) -> f64 {
+        let refilled = now.duration_since(at).as_secs_f64() * self.refill_per_sec;
+        (tokens + refilled).min(self.capacity)
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+/// The types of the avatar messages defined in 'client/src/avatar/messaging.ts'.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AvatarMessageType {
+    Opened,
+    Joined,
+    Hello,
+    HelloResponse,
+    Move,
+    Stop,
+    Bye,
+}
+
+impl AvatarMessageType {
+    fn from_u8(value: u8) -> Option<AvatarMessageType> {
+        match value {
+            0 => Some(AvatarMessageType::Opened),
+            1 => Some(AvatarMessageType::Joined),
+            2 => Some(AvatarMessageType::Hello),
+            3 => Some(AvatarMessageType::HelloResponse),
+            4 => Some(AvatarMessageType::Move),
+            5 => Some(AvatarMessageType::Stop),
+            6 => Some(AvatarMessageType::Bye),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub struct Coord {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Coord {
+    pub fn distance(&self, other: &Coord) -> f64 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    }
+}
+
+/// An avatar message relayed through the data channels.
+///
+/// Only the fields the SFU is interested in are parsed.
+///
+#[derive(Deserialize, Debug)]
+pub struct AvatarMessage {
+    #[serde(rename = "msgType")]
+    msg_type: u8,
+    pub id: String,
+    pub name: Option<String>,
+    pub direction: Option<u8>,
+    pub coord: Option<Coord>,
+}
+
+impl AvatarMessage {
+    pub fn parse(message: &str) -> Option<AvatarMessage> {
+        serde_json::from_str(message).ok()
+    }
+
+    pub fn message_type(&self) -> Option<AvatarMessageType> {
+        AvatarMessageType::from_u8(self.msg_type)
+    }
+
+    /// Whether the message describes the avatar, whose state it then updates.
+    ///
+    fn describes_avatar(&self) -> bool {
+        matches!(
+            self.message_type(),
+            Some(AvatarMessageType::Hello)
+                | Some(AvatarMessageType::HelloResponse)
+                | Some(AvatarMessageType::Move)
+        )
+    }
+
+    /// Whether the message places the avatar, which is what makes the avatar known.
+    ///
+    pub fn carries_position(&self) -> bool {
+        self.describes_avatar() && self.coord.is_some()
+    }
+}
+
+/// The latest state of an avatar known from the relayed messages.
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct AvatarState {
+    #[serde(rename = "msgType")]
+    msg_type: u8,
+    id: String,
+    name: String,
+    direction: u8,
+    coord: Coord,
+}
+
+impl AvatarState {
+    /// Creates a state placed at the origin, where the client puts a new avatar.
+    ///
+    pub fn new(id: String, name: String) -> Self {
+        AvatarState {
+            msg_type: AvatarMessageType::HelloResponse as u8,
+            id,
+            name,
+            direction: 0,
+            coord: Coord { x: 0.0, y: 0.0 },
+        }
+    }
+
+    pub fn coord(&self) -> &Coord {
+        &self.coord
+    }
+
+    /// Applies a message sent by the avatar and returns whether its position has changed.
+    ///
+    pub fn apply(&mut self, message: &AvatarMessage) -> bool {
+        if !message.describes_avatar() {
+            return false;
+        }
+
+        self.id = message.id.clone();
+        if let Some(name) = &message.name {
+            self.name = name.clone();
+        }
+        if let Some(direction) = message.direction {
+            self.direction = direction;
+        }
+        match message.coord {
+            Some(coord) if coord != self.coord => {
+                self.coord = coord;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Serializes the state as a 'HelloResponse' message
+    /// so that clients can place the avatar just as if the peer had answered.
+    ///
+    pub fn to_hello_response(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::rtp_transceiver::RTCPFeedback;
+
+use log::warn;
+
+use crate::config;
+
+const MIME_TYPE_AV1: &str = "video/AV1";
+
+/// The profiles of H.264 told apart by the first byte of 'profile-level-id'
+/// and the constraint flags in the second.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum H264Profile {
+    ConstrainedBaseline,
+    Baseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    fn profile_level_id(self) -> &'static str {
+        match self {
+            H264Profile::ConstrainedBaseline => "42e01f",
+            H264Profile::Baseline => "42001f",
+            H264Profile::Main => "4d001f",
+            H264Profile::High => "64001f",
+        }
+    }
+
+    fn of(profile_level_id: &str) -> Option<H264Profile> {
+        let profile_idc = u8::from_str_radix(profile_level_id.get(0..2)?, 16).ok()?;
+        let profile_iop = u8::from_str_radix(profile_level_id.get(2..4)?, 16).ok()?;
+        match profile_idc {
+            // constraint_set1_flag makes Baseline the subset Main decoders can decode.
+            0x42 if profile_iop & 0x40 != 0 => Some(H264Profile::ConstrainedBaseline),
+            0x42 => Some(H264Profile::Baseline),
+            0x4d => Some(H264Profile::Main),
+            0x64 => Some(H264Profile::High),
+            _ => None,
+        }
+    }
+}
+
+/// The codecs the SFU can forward.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CodecName {
+    Vp8,
+    Vp9,
+    H264(H264Profile),
+    Av1,
+    Opus,
+}
+
+impl FromStr for CodecName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vp8" => Ok(CodecName::Vp8),
+            "vp9" => Ok(CodecName::Vp9),
+            "h264" => Ok(CodecName::H264(H264Profile::ConstrainedBaseline)),
+            "h264-baseline" => Ok(CodecName::H264(H264Profile::Baseline)),
+            "h264-main" => Ok(CodecName::H264(H264Profile::Main)),
+            "h264-high" => Ok(CodecName::H264(H264Profile::High)),
+            "av1" => Ok(CodecName::Av1),
+            "opus" => Ok(CodecName::Opus),
+            _ => Err(format!("Unknown codec {:?}.", s)),
+        }
+    }
+}
+
+impl fmt::Display for CodecName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecName::H264(profile) => write!(f, "H264 {:?}", profile),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl CodecName {
+    pub fn kind(self) -> RTPCodecType {
+        match self {
+            CodecName::Opus => RTPCodecType::Audio,
+            _ => RTPCodecType::Video,
+        }
+    }
+
+    /// The codec of a track, or None if the SFU doesn't know it.
+    ///
+    pub fn of(capability: &RTCRtpCodecCapability) -> Option<CodecName> {
+        CodecName::from_rtpmap(&capability.mime_type, &capability.sdp_fmtp_line)
+    }
+
+    /// Tells the codec from its MIME type or encoding name and its format parameters.
+    ///
+    fn from_rtpmap(mime_type: &str, fmtp: &str) -> Option<CodecName> {
+        let name = mime_type.rsplit('/').next()?.to_ascii_lowercase();
+        match name.as_str() {
+            "vp8" => Some(CodecName::Vp8),
+            "vp9" => Some(CodecName::Vp9),
+            "av1" => Some(CodecName::Av1),
+            "opus" => Some(CodecName::Opus),
+            "h264" => fmtp
+                .split(';')
+                .filter_map(|parameter| parameter.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("profile-level-id"))
+                .and_then(|(_, value)| H264Profile::of(value))
+                // The profile is Baseline if omitted.
+                .or(Some(H264Profile::Baseline))
+                .map(CodecName::H264),
+            _ => None,
+        }
+    }
+
+    fn parameters(self, opus_fmtp: &str) -> RTCRtpCodecParameters {
+        let video_rtcp_feedback = vec![
+            RTCPFeedback {
+                typ: "goog-remb".to_owned(),
+                parameter: "".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "ccm".to_owned(),
+                parameter: "fir".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: "".to_owned(),
+            },
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: "pli".to_owned(),
+            },
+        ];
+        let video = |mime_type: &str, sdp_fmtp_line: String| RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate: 90000,
+            channels: 0,
+            sdp_fmtp_line,
+            rtcp_feedback: video_rtcp_feedback.clone(),
+        };
+
+        let (capability, payload_type) = match self {
+            CodecName::Vp8 => (video(MIME_TYPE_VP8, "".to_owned()), 96),
+            CodecName::Vp9 => (video(MIME_TYPE_VP9, "profile-id=0".to_owned()), 98),
+            CodecName::H264(profile) => (
+                video(
+                    MIME_TYPE_H264,
+                    format!(
+                        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={}",
+                        profile.profile_level_id()
+                    ),
+                ),
+                match profile {
+                    H264Profile::ConstrainedBaseline => 102,
+                    H264Profile::Baseline => 104,
+                    H264Profile::Main => 106,
+                    H264Profile::High => 108,
+                },
+            ),
+            CodecName::Av1 => (video(MIME_TYPE_AV1, "".to_owned()), 45),
+            CodecName::Opus => (
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_owned(),
+                    clock_rate: 48000,
+                    channels: 2,
+                    sdp_fmtp_line: opus_fmtp.to_owned(),
+                    rtcp_feedback: vec![],
+                },
+                111,
+            ),
+        };
+
+        RTCRtpCodecParameters {
+            capability,
+            payload_type,
+            ..Default::default()
+        }
+    }
+}
+
+/// The codecs allowed in the order of priority, and the options of Opus.
+///
+/// The server's policy decides the codecs the SFU negotiates at all. A room may narrow them
+/// down or reorder them with the codecs stored with the room.
+///
+#[derive(Debug, Clone)]
+pub struct CodecPolicy {
+    pub video: Vec<CodecName>,
+    pub audio: Vec<CodecName>,
+    /// Whether Opus carries forward error correction.
+    pub opus_fec: bool,
+    /// Whether Opus stops sending during silence.
+    pub opus_dtx: bool,
+}
+
+impl CodecPolicy {
+    pub fn from_env() -> Self {
+        let video: String = config::env_or("VIDEO_CODECS", "vp8,vp9,h264".to_owned());
+        let audio: String = config::env_or("AUDIO_CODECS", "opus".to_owned());
+        let video = match parse_codecs(&video, RTPCodecType::Video) {
+            codecs if codecs.is_empty() => {
+                warn!("VIDEO_CODECS has no video codec so allow VP8.");
+                vec![CodecName::Vp8]
+            }
+            codecs => codecs,
+        };
+        let audio = match parse_codecs(&audio, RTPCodecType::Audio) {
+            codecs if codecs.is_empty() => {
+                warn!("AUDIO_CODECS has no audio codec so allow Opus.");
+                vec![CodecName::Opus]
+            }
+            codecs => codecs,
+        };
+
+        CodecPolicy {
+            video,
+            audio,
+            opus_fec: config::env_or("OPUS_FEC", true),
+            opus_dtx: config::env_or("OPUS_DTX", false),
+        }
+    }
+
+    /// The policy of a room overriding the server's one with the codecs stored with the room.
+    ///
+    /// Codecs the server doesn't allow are ignored, and the server's codecs of a kind are used
+    /// when the room allows none of them.
+    ///
+    pub fn for_room(&self, video: Option<&str>, audio: Option<&str>) -> CodecPolicy {
+        let narrow = |codecs: Option<&str>, allowed: &Vec<CodecName>, kind: RTPCodecType| {
+            let codecs = match codecs {
+                Some(codecs) => parse_codecs(codecs, kind),
+                None => return allowed.clone(),
+            };
+            let narrowed: Vec<CodecName> = codecs
+                .into_iter()
+                .filter(|codec| {
+                    let is_allowed = allowed.contains(codec);
+                    if !is_allowed {
+                        warn!("{} isn't allowed on this server so ignore it.", codec);
+                    }
+                    is_allowed
+                })
+                .collect();
+            if narrowed.is_empty() {
+                warn!(
+                    "The room allows no {:?} codec so use the server's ones.",
+                    kind
+                );
+                return allowed.clone();
+            }
+            narrowed
+        };
+
+        CodecPolicy {
+            video: narrow(video, &self.video, RTPCodecType::Video),
+            audio: narrow(audio, &self.audio, RTPCodecType::Audio),
+            ..self.clone()
+        }
+    }
+
+    fn opus_fmtp(&self) -> String {
+        let mut fmtp = "minptime=10".to_owned();
+        if self.opus_fec {
+            fmtp.push_str(";useinbandfec=1");
+        }
+        if self.opus_dtx {
+            fmtp.push_str(";usedtx=1");
+        }
+        fmtp
+    }
+
+    /// Registers the codecs instead of the default ones of webrtc-rs.
+    ///
+    pub fn register(&self, m: &mut MediaEngine) -> Result<(), webrtc::Error> {
+        for codec in self.video.iter().chain(self.audio.iter()) {
+            m.register_codec(codec.parameters(&self.opus_fmtp()), codec.kind())?;
+        }
+        Ok(())
+    }
+
+    /// The codecs of the kind in the order of priority.
+    ///
+    pub fn parameters(&self, kind: RTPCodecType) -> Vec<RTCRtpCodecParameters> {
+        let codecs = if kind == RTPCodecType::Audio {
+            &self.audio
+        } else {
+            &self.video
+        };
+        codecs
+            .iter()
+            .map(|codec| codec.parameters(&self.opus_fmtp()))
+            .collect()
+    }
+
+    /// Restricts the transceivers of the peer connection to the codecs in the order of priority,
+    /// so that publishers pick the first codec they support.
+    ///
+    pub async fn apply(&self, pc: &RTCPeerConnection) -> Result<(), webrtc::Error> {
+        for transceiver in pc.get_transceivers().await {
+            let kind = transceiver.kind();
+            if kind != RTPCodecType::Audio && kind != RTPCodecType::Video {
+                continue;
+            }
+            transceiver
+                .set_codec_preferences(self.parameters(kind))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_codecs(codecs: &str, kind: RTPCodecType) -> Vec<CodecName> {
+    let mut parsed = vec![];
+    for codec in codecs
+        .split(',')
+        .map(|codec| codec.trim().to_ascii_lowercase())
+        .filter(|codec| !codec.is_empty())
+    {
+        match codec.parse::<CodecName>() {
+            Ok(codec) if codec.kind() != kind => {
+                warn!("{} isn't a {:?} codec so ignore it.", codec, kind)
+            }
+            Ok(codec) if !parsed.contains(&codec) => parsed.push(codec),
+            Ok(_) => {}
+            Err(e) => warn!("{} Ignore it.", e),
+        }
+    }
+    parsed
+}
+
+/// The codecs in the 'rtpmap' attributes of an SDP, which a browser lists in the order of its
+/// preference among those it can decode.
+///
+pub fn decodable_codecs(sdp: &str) -> HashSet<CodecName> {
+    let lines: Vec<&str> = sdp.lines().map(|line| line.trim()).collect();
+    let fmtp_of = |payload_type: &str| {
+        let prefix = format!("a=fmtp:{} ", payload_type);
+        lines
+            .iter()
+            .copied()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap_or("")
+    };
+
+    lines
+        .iter()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| rtpmap.split_once(' '))
+        .filter_map(|(payload_type, encoding)| {
+            let name = encoding.split('/').next()?;
+            CodecName::from_rtpmap(name, fmtp_of(payload_type))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(video: Vec<CodecName>, audio: Vec<CodecName>) -> CodecPolicy {
+        CodecPolicy {
+            video,
+            audio,
+            opus_fec: true,
+            opus_dtx: false,
+        }
+    }
+
+    #[test]
+    fn h264_profile_of_profile_level_id() {
+        assert_eq!(
+            H264Profile::of("42e01f"),
+            Some(H264Profile::ConstrainedBaseline)
+        );
+        // Only constraint_set1_flag tells Constrained Baseline apart.
+        assert_eq!(
+            H264Profile::of("42401f"),
+            Some(H264Profile::ConstrainedBaseline)
+        );
+        assert_eq!(H264Profile::of("42001f"), Some(H264Profile::Baseline));
+        assert_eq!(H264Profile::of("42801f"), Some(H264Profile::Baseline));
+        assert_eq!(H264Profile::of("4d001f"), Some(H264Profile::Main));
+        assert_eq!(H264Profile::of("64001f"), Some(H264Profile::High));
+        assert_eq!(H264Profile::of("640C1F"), Some(H264Profile::High));
+
+        // High 10 and malformed values aren't known.
+        assert_eq!(H264Profile::of("6e001f"), None);
+        assert_eq!(H264Profile::of("4"), None);
+        assert_eq!(H264Profile::of("zz001f"), None);
+        assert_eq!(H264Profile::of(""), None);
+    }
+
+    #[test]
+    fn codec_name_from_rtpmap() {
+        assert_eq!(
+            CodecName::from_rtpmap("video/VP8", ""),
+            Some(CodecName::Vp8)
+        );
+        assert_eq!(CodecName::from_rtpmap("VP9", ""), Some(CodecName::Vp9));
+        assert_eq!(
+            CodecName::from_rtpmap("video/av1", ""),
+            Some(CodecName::Av1)
+        );
+        assert_eq!(
+            CodecName::from_rtpmap("audio/opus", ""),
+            Some(CodecName::Opus)
+        );
+
+        assert_eq!(
+            CodecName::from_rtpmap(
+                "video/H264",
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
+            ),
+            Some(CodecName::H264(H264Profile::ConstrainedBaseline))
+        );
+        assert_eq!(
+            CodecName::from_rtpmap("H264", "packetization-mode=1; Profile-Level-Id=640032"),
+            Some(CodecName::H264(H264Profile::High))
+        );
+        // The profile is Baseline when omitted or unknown.
+        assert_eq!(
+            CodecName::from_rtpmap("H264", "packetization-mode=1"),
+            Some(CodecName::H264(H264Profile::Baseline))
+        );
+        assert_eq!(
+            CodecName::from_rtpmap("H264", "profile-level-id=f4001f"),
+            Some(CodecName::H264(H264Profile::Baseline))
+        );
+
+        assert_eq!(CodecName::from_rtpmap("video/H265", ""), None);
+        assert_eq!(CodecName::from_rtpmap("video/rtx", "apt=96"), None);
+        assert_eq!(CodecName::from_rtpmap("", ""), None);
+    }
+
+    #[test]
+    fn codec_name_from_str() {
+        assert_eq!("vp8".parse(), Ok(CodecName::Vp8));
+        assert_eq!(
+            "h264".parse(),
+            Ok(CodecName::H264(H264Profile::ConstrainedBaseline))
+        );
+        assert_eq!("h264-high".parse(), Ok(CodecName::H264(H264Profile::High)));
+        assert!("h265".parse::<CodecName>().is_err());
+        assert!("VP8".parse::<CodecName>().is_err());
+    }
+
+    #[test]
+    fn parse_codecs_skips_unknown_duplicated_and_other_kinds() {
+        assert_eq!(
+            parse_codecs(" VP9, vp8,h265,opus,,vp9 ", RTPCodecType::Video),
+            vec![CodecName::Vp9, CodecName::Vp8]
+        );
+        assert_eq!(
+            parse_codecs("vp8,opus", RTPCodecType::Audio),
+            vec![CodecName::Opus]
+        );
+    }
+
+    #[test]
+    fn room_policy_narrows_down_server_policy() {
+        let server = policy(
+            vec![
+                CodecName::Vp8,
+                CodecName::Vp9,
+                CodecName::H264(H264Profile::ConstrainedBaseline),
+            ],
+            vec![CodecName::Opus],
+        );
+
+        // The room may reorder the server's codecs.
+        let room = server.for_room(Some("h264,vp8"), None);
+        assert_eq!(
+            room.video,
+            vec![
+                CodecName::H264(H264Profile::ConstrainedBaseline),
+                CodecName::Vp8
+            ]
+        );
+        assert_eq!(room.audio, vec![CodecName::Opus]);
+        assert!(room.opus_fec);
+
+        // Codecs the server doesn't allow are ignored.
+        let room = server.for_room(Some("av1,h264-high,vp9"), None);
+        assert_eq!(room.video, vec![CodecName::Vp9]);
+        assert!(!room.video.contains(&CodecName::Av1));
+
+        // The server's codecs are used when the room allows none of them.
+        let room = server.for_room(Some("av1,h265"), Some("vp8"));
+        assert_eq!(room.video, server.video);
+        assert_eq!(room.audio, server.audio);
+
+        let room = server.for_room(None, None);
+        assert_eq!(room.video, server.video);
+    }
+
+    #[test]
+    fn decodable_codecs_of_sdp() {
+        let sdp = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\n\
+            a=rtpmap:111 opus/48000/2\r\n\
+            a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+            a=rtpmap:0 PCMU/8000\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96 97 102 108 123\r\n\
+            a=rtpmap:96 VP8/90000\r\n\
+            a=rtpmap:97 rtx/90000\r\n\
+            a=fmtp:97 apt=96\r\n\
+            a=rtpmap:102 H264/90000\r\n\
+            a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n\
+            a=rtpmap:108 H264/90000\r\n\
+            a=fmtp:108 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=4d001f\r\n\
+            a=rtpmap:123 H265/90000\r\n";
+
+        let expected: HashSet<CodecName> = [
+            CodecName::Opus,
+            CodecName::Vp8,
+            CodecName::H264(H264Profile::ConstrainedBaseline),
+            CodecName::H264(H264Profile::Main),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(decodable_codecs(sdp), expected);
+
+        assert!(decodable_codecs("").is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+use std::env;
+use std::str::FromStr;
+
+use log::{info, warn};
+
+/// Reads a setting from the environment variable named 'key'.
+///
+/// The default value is used when the variable is not specified or can't be parsed.
+///
+pub fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr + std::fmt::Debug,
+{
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!(
+                    "{} has an invalid value {:?} so use the default value {:?}.",
+                    key, value, default
+                );
+                default
+            }
+        },
+        Err(_) => {
+            info!(
+                "{} is not specified so use the default value {:?}.",
+                key, default
+            );
+            default
+        }
+    }
+}
@@ -13,7 +13,12 @@ pub struct RoomMember {
 	pub member_id: i64,
 	pub room_id: i64,
 	pub room_name: String,
-	pub member_name: String	
+	pub member_name: String,
+	pub last_n: Option<i32>,
+	pub duplicate_session_policy: Option<String>,
+	/// The comma separated codecs overriding VIDEO_CODECS and AUDIO_CODECS in the room.
+	pub video_codecs: Option<String>,
+	pub audio_codecs: Option<String>
 }
 
 pub struct MemberToken {
@@ -72,7 +77,11 @@ impl RoomMemberDao {
 					m.member_id as member_id,
 					m.room_id as room_id,
 					r.room_name as room_name,
-					m.member_name as member_name
+					m.member_name as member_name,
+					r.last_n as last_n,
+					r.duplicate_session_policy as duplicate_session_policy,
+					r.video_codecs as video_codecs,
+					r.audio_codecs as audio_codecs
 				FROM
 					myappsch.members m
 						INNER JOIN
@@ -86,10 +95,10 @@ impl RoomMemberDao {
 		";
 
 		let result = client
-			.query_one(sql, &[member_id, secret_token])
+			.query_opt(sql, &[member_id, secret_token])
 			.await?;
 		
-		Ok(row_to_room_member(&result))
+		result.map(|row| row_to_room_member(&row)).ok_or(ApplicationError::InvalidToken)
 	}
 }
 
@@ -98,6 +107,10 @@ fn row_to_room_member(row: &Row) -> RoomMember {
 		member_id: row.get(0),
 		room_id: row.get(1),
 		room_name: row.get(2),
-		member_name: row.get(3)
+		member_name: row.get(3),
+		last_n: row.get(4),
+		duplicate_session_policy: row.get(5),
+		video_codecs: row.get(6),
+		audio_codecs: row.get(7)
 	}
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 
-use warp::{reject, ws::Message};
+use schemars::JsonSchema;
 use serde::Serialize;
+use warp::{http::StatusCode, reject};
 
-type WsSendError = tokio::sync::mpsc::error::SendError<Message>;
+use crate::queue::QueueError;
 
 #[derive(Debug)]
 pub enum ApplicationError {
@@ -11,11 +12,12 @@ pub enum ApplicationError {
     Json(serde_json::Error),
     Web(warp::Error),
     WebRTC(webrtc::Error),
-    WsSend(WsSendError),
+    Queue,
     DBPool(mobc::Error<tokio_postgres::Error>),
     DB(tokio_postgres::Error),
     Base64(base64::DecodeError),
-    Decode(std::string::FromUtf8Error)
+    Decode(std::string::FromUtf8Error),
+    InvalidToken,
 }
 
 impl From<()> for ApplicationError {
@@ -42,9 +44,9 @@ impl From<webrtc::Error> for ApplicationError {
     }
 }
 
-impl From<WsSendError> for ApplicationError {
-    fn from(item: WsSendError) -> Self {
-        ApplicationError::WsSend(item)
+impl From<QueueError> for ApplicationError {
+    fn from(_: QueueError) -> Self {
+        ApplicationError::Queue
     }
 }
 
@@ -74,8 +76,90 @@ impl From<std::string::FromUtf8Error> for ApplicationError {
 
 impl reject::Reject for ApplicationError {}
 
-#[derive(Serialize)]
-pub struct ErrorMessage {
-    pub code: u16,
+impl ApplicationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ApplicationError::Json(_) => ErrorCode::InvalidMessage,
+            ApplicationError::WebRTC(_) => ErrorCode::NegotiationFailed,
+            ApplicationError::InvalidToken => ErrorCode::Unauthorized,
+            ApplicationError::DBPool(_) => ErrorCode::ServiceUnavailable,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// The stable codes of the errors told to clients, over both REST and signaling.
+/// The auth service uses the same codes.
+///
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is malformed.
+    InvalidRequest,
+    /// The member token is missing or invalid.
+    Unauthorized,
+    /// The origin isn't allowed.
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    /// The client IP or member has exceeded a rate limit, so it should retry later.
+    RateLimited,
+    /// A dependency such as the database is down, so the client should retry later.
+    ServiceUnavailable,
+    /// The signaling message can't be parsed.
+    InvalidMessage,
+    /// The SDP or ICE candidate in the signaling message can't be applied.
+    NegotiationFailed,
+    /// The SFU speaks no version of the signaling protocol the peer does.
+    UnsupportedProtocolVersion,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest
+            | ErrorCode::InvalidMessage
+            | ErrorCode::NegotiationFailed
+            | ErrorCode::UnsupportedProtocolVersion => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The body of error responses and of 'error' signaling messages.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
     pub message: String,
 }
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        ErrorBody {
+            code,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl From<&ApplicationError> for ErrorBody {
+    fn from(e: &ApplicationError) -> Self {
+        let code = e.code();
+        let message = match e {
+            ApplicationError::Json(e) => e.to_string(),
+            ApplicationError::WebRTC(e) => e.to_string(),
+            ApplicationError::InvalidToken => "Invalid token.".to_owned(),
+            ApplicationError::DBPool(_) => "The database is unavailable.".to_owned(),
+            _ => "Internal error.".to_owned(),
+        };
+        ErrorBody { code, message }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use webrtc::rtcp::reception_report::ReceptionReport;
+
+/// Reports not renewed within this many intervals are regarded as gone with their subscribers.
+const REPORT_EXPIRATION_INTERVALS: u32 = 5;
+
+/// The reception of a publisher's video as reported by its subscribers.
+///
+/// The worst of the subscribers' values are taken so that the publisher adapts
+/// to the subscriber with the poorest network. The packets lost and the last sequence number
+/// are zero if the subscribers receive the video with rewritten sequence numbers.
+///
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Reception {
+    pub fraction_lost: u8,
+    pub total_lost: u32,
+    pub last_sequence_number: u32,
+    pub jitter: u32,
+    pub subscribers: usize,
+}
+
+impl Reception {
+    fn of(report: &ReceptionReport) -> Self {
+        Reception {
+            fraction_lost: report.fraction_lost,
+            total_lost: report.total_lost,
+            last_sequence_number: report.last_sequence_number,
+            jitter: report.jitter,
+            subscribers: 1,
+        }
+    }
+
+    fn merge(self, another: Reception) -> Self {
+        Reception {
+            fraction_lost: self.fraction_lost.max(another.fraction_lost),
+            total_lost: self.total_lost.max(another.total_lost),
+            last_sequence_number: self.last_sequence_number.max(another.last_sequence_number),
+            jitter: self.jitter.max(another.jitter),
+            subscribers: self.subscribers + another.subscribers,
+        }
+    }
+
+    /// The reception as a report block on the publisher's SSRC.
+    ///
+    /// The SFU doesn't relay the publisher's sender reports,
+    /// so the block carries no round-trip time.
+    ///
+    pub fn to_report(self, ssrc: u32) -> ReceptionReport {
+        ReceptionReport {
+            ssrc,
+            fraction_lost: self.fraction_lost,
+            total_lost: self.total_lost,
+            last_sequence_number: self.last_sequence_number,
+            jitter: self.jitter,
+            last_sender_report: 0,
+            delay: 0,
+        }
+    }
+}
+
+/// Aggregates the receiver reports the subscribers send on the video of each publisher,
+/// so that a publisher receives one report per interval however many subscribers it has.
+///
+pub struct ReceptionAggregator {
+    interval: Duration,
+    reports: HashMap<Uuid, HashMap<Uuid, (Reception, Instant)>>,
+    reported_at: HashMap<Uuid, Instant>,
+}
+
+impl ReceptionAggregator {
+    pub fn new(interval: Duration) -> Self {
+        ReceptionAggregator {
+            interval,
+            reports: HashMap::new(),
+            reported_at: HashMap::new(),
+        }
+    }
+
+    /// Records the report a subscriber sent on the publisher's video
+    /// and returns the aggregated reception when the publisher is due to be told.
+    ///
+    pub fn update(
+        &mut self,
+        pub_id: &Uuid,
+        sub_id: &Uuid,
+        report: &ReceptionReport,
+        now: Instant,
+    ) -> Option<Reception> {
+        self.reports
+            .entry(pub_id.clone())
+            .or_default()
+            .insert(sub_id.clone(), (Reception::of(report), now));
+
+        if let Some(reported_at) = self.reported_at.get(pub_id) {
+            if now.duration_since(*reported_at) < self.interval {
+                return None;
+            }
+        }
+        self.reported_at.insert(pub_id.clone(), now);
+
+        self.aggregate(pub_id, now)
+    }
+
+    /// Removes the reports of the peer both as a publisher and as a subscriber.
+    ///
+    pub fn remove(&mut self, peer_id: &Uuid) {
+        self.reports.remove(peer_id);
+        self.reported_at.remove(peer_id);
+        for reports in self.reports.values_mut() {
+            reports.remove(peer_id);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn holds(&self, peer_id: &Uuid) -> bool {
+        self.reports.contains_key(peer_id)
+            || self.reported_at.contains_key(peer_id)
+            || self.reports.values().any(|r| r.contains_key(peer_id))
+    }
+
+    fn aggregate(&mut self, pub_id: &Uuid, now: Instant) -> Option<Reception> {
+        let expiration = self.interval * REPORT_EXPIRATION_INTERVALS;
+        let reports = self.reports.get_mut(pub_id)?;
+        reports.retain(|_, (_, updated_at)| now.duration_since(*updated_at) < expiration);
+
+        reports
+            .values()
+            .map(|(reception, _)| *reception)
+            .reduce(Reception::merge)
+    }
+}
+
+/// The RTCP packets that ask a publisher for a keyframe.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyframeRequest {
+    Pli,
+    Fir,
+}
+
+/// The counts of the keyframe requests to a publisher, shared with the metrics of the peer.
+///
+#[derive(Debug, Clone, Default)]
+pub struct KeyframeRequestMetrics {
+    requested: Arc<AtomicUsize>,
+    sent: Arc<AtomicUsize>,
+    suppressed: Arc<AtomicUsize>,
+}
+
+impl fmt::Display for KeyframeRequestMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "keyframe requests {} ({} sent, {} suppressed)",
+            self.requested.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            self.suppressed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Coalesces the keyframe requests to a publisher so that at most one is sent per interval.
+///
+/// Requests within the interval after a sent one are suppressed, and one request is sent
+/// for all of them when the interval has passed, so that subscribers that asked after the
+/// keyframe had been requested still receive one. FIR is sent if any of them is FIR.
+///
+pub struct KeyframeRequestThrottle {
+    min_interval: Duration,
+    sent_at: Option<tokio::time::Instant>,
+    pending: Option<KeyframeRequest>,
+    metrics: KeyframeRequestMetrics,
+}
+
+impl KeyframeRequestThrottle {
+    pub fn new(min_interval: Duration, metrics: KeyframeRequestMetrics) -> Self {
+        KeyframeRequestThrottle {
+            min_interval,
+            sent_at: None,
+            pending: None,
+            metrics,
+        }
+    }
+
+    /// Returns the request to send now, or None if it is coalesced into the next one.
+    ///
+    pub fn request(
+        &mut self,
+        request: KeyframeRequest,
+        now: tokio::time::Instant,
+    ) -> Option<KeyframeRequest> {
+        self.metrics.requested.fetch_add(1, Ordering::Relaxed);
+        match self.sent_at {
+            Some(sent_at) if now.duration_since(sent_at) < self.min_interval => {
+                self.metrics.suppressed.fetch_add(1, Ordering::Relaxed);
+                if self.pending != Some(KeyframeRequest::Fir) {
+                    self.pending = Some(request);
+                }
+                None
+            }
+            _ => self.send(request, now),
+        }
+    }
+
+    /// When the coalesced request is due, if any.
+    ///
+    pub fn due_at(&self) -> Option<tokio::time::Instant> {
+        self.pending
+            .and(self.sent_at)
+            .map(|sent_at| sent_at + self.min_interval)
+    }
+
+    /// Returns the coalesced request once it is due.
+    ///
+    pub fn take_due(&mut self, now: tokio::time::Instant) -> Option<KeyframeRequest> {
+        if self.due_at()? > now {
+            return None;
+        }
+        let request = self.pending.take()?;
+        self.send(request, now)
+    }
+
+    fn send(
+        &mut self,
+        request: KeyframeRequest,
+        now: tokio::time::Instant,
+    ) -> Option<KeyframeRequest> {
+        self.sent_at = Some(now);
+        self.pending = None;
+        self.metrics.sent.fetch_add(1, Ordering::Relaxed);
+        Some(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(1000);
+
+    fn report(fraction_lost: u8, total_lost: u32, jitter: u32) -> ReceptionReport {
+        ReceptionReport {
+            ssrc: 1,
+            fraction_lost,
+            total_lost,
+            last_sequence_number: 100,
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn aggregator_reports_worst_reception_once_per_interval() {
+        let mut aggregator = ReceptionAggregator::new(INTERVAL);
+        let pub_id = Uuid::new_v4();
+        let sub_id = Uuid::new_v4();
+        let another_sub_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        assert_eq!(
+            aggregator.update(&pub_id, &sub_id, &report(10, 5, 30), start),
+            Some(Reception {
+                fraction_lost: 10,
+                total_lost: 5,
+                last_sequence_number: 100,
+                jitter: 30,
+                subscribers: 1,
+            })
+        );
+        // Reports within the interval are only recorded.
+        assert_eq!(
+            aggregator.update(
+                &pub_id,
+                &another_sub_id,
+                &report(40, 2, 50),
+                start + INTERVAL / 2
+            ),
+            None
+        );
+
+        let reception = aggregator.update(&pub_id, &sub_id, &report(20, 6, 10), start + INTERVAL);
+        assert_eq!(
+            reception,
+            Some(Reception {
+                fraction_lost: 40,
+                total_lost: 6,
+                last_sequence_number: 100,
+                jitter: 50,
+                subscribers: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn aggregator_forgets_reports_not_renewed() {
+        let mut aggregator = ReceptionAggregator::new(INTERVAL);
+        let pub_id = Uuid::new_v4();
+        let sub_id = Uuid::new_v4();
+        let another_sub_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        aggregator.update(&pub_id, &another_sub_id, &report(90, 50, 80), start);
+        let now = start + INTERVAL * REPORT_EXPIRATION_INTERVALS;
+        let reception = aggregator.update(&pub_id, &sub_id, &report(1, 1, 1), now);
+        assert_eq!(
+            reception.map(|r| (r.fraction_lost, r.subscribers)),
+            Some((1, 1))
+        );
+    }
+
+    #[test]
+    fn aggregator_removes_peer_as_publisher_and_subscriber() {
+        let mut aggregator = ReceptionAggregator::new(INTERVAL);
+        let peer_id = Uuid::new_v4();
+        let another_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        aggregator.update(&peer_id, &another_id, &report(1, 1, 1), start);
+        aggregator.update(&another_id, &peer_id, &report(1, 1, 1), start);
+        assert!(aggregator.holds(&peer_id));
+
+        aggregator.remove(&peer_id);
+        assert!(!aggregator.holds(&peer_id));
+        assert!(aggregator.holds(&another_id));
+
+        // The other publisher is told no reception once it has no subscriber.
+        assert_eq!(aggregator.aggregate(&another_id, start + INTERVAL), None);
+    }
+
+    #[test]
+    fn reception_to_report_is_on_publisher_ssrc() {
+        let reception = Reception {
+            fraction_lost: 3,
+            total_lost: 4,
+            last_sequence_number: 5,
+            jitter: 6,
+            subscribers: 2,
+        };
+        let report = reception.to_report(1234);
+        assert_eq!(report.ssrc, 1234);
+        assert_eq!(report.fraction_lost, 3);
+        assert_eq!(report.total_lost, 4);
+        assert_eq!(report.last_sequence_number, 5);
+        assert_eq!(report.jitter, 6);
+        assert_eq!(report.last_sender_report, 0);
+    }
+
+    fn throttle() -> (KeyframeRequestThrottle, KeyframeRequestMetrics) {
+        let metrics = KeyframeRequestMetrics::default();
+        (
+            KeyframeRequestThrottle::new(Duration::from_millis(500), metrics.clone()),
+            metrics,
+        )
+    }
+
+    #[test]
+    fn throttle_suppresses_requests_within_interval() {
+        let (mut throttle, metrics) = throttle();
+        let start = tokio::time::Instant::now();
+
+        assert_eq!(
+            throttle.request(KeyframeRequest::Pli, start),
+            Some(KeyframeRequest::Pli)
+        );
+        assert_eq!(throttle.due_at(), None);
+
+        for i in 1..=3 {
+            let now = start + Duration::from_millis(100 * i);
+            assert_eq!(throttle.request(KeyframeRequest::Pli, now), None);
+            assert_eq!(throttle.take_due(now), None);
+        }
+        assert_eq!(throttle.due_at(), Some(start + Duration::from_millis(500)));
+        assert_eq!(
+            metrics.to_string(),
+            "keyframe requests 4 (1 sent, 3 suppressed)"
+        );
+    }
+
+    #[test]
+    fn throttle_sends_one_coalesced_request_once_interval_elapses() {
+        let (mut throttle, metrics) = throttle();
+        let start = tokio::time::Instant::now();
+        throttle.request(KeyframeRequest::Pli, start);
+        throttle.request(KeyframeRequest::Pli, start + Duration::from_millis(100));
+        throttle.request(KeyframeRequest::Pli, start + Duration::from_millis(200));
+
+        let due_at = start + Duration::from_millis(500);
+        assert_eq!(throttle.take_due(due_at), Some(KeyframeRequest::Pli));
+        assert_eq!(throttle.take_due(due_at), None);
+        assert_eq!(throttle.due_at(), None);
+        assert_eq!(
+            metrics.to_string(),
+            "keyframe requests 3 (2 sent, 2 suppressed)"
+        );
+
+        // The coalesced request starts the next interval.
+        assert_eq!(
+            throttle.request(KeyframeRequest::Pli, due_at + Duration::from_millis(100)),
+            None
+        );
+        assert_eq!(
+            throttle.request(KeyframeRequest::Pli, due_at + Duration::from_millis(500)),
+            Some(KeyframeRequest::Pli)
+        );
+    }
+
+    #[test]
+    fn throttle_coalesces_into_fir_if_any_request_is_fir() {
+        let (mut throttle, _) = throttle();
+        let start = tokio::time::Instant::now();
+        throttle.request(KeyframeRequest::Pli, start);
+        throttle.request(KeyframeRequest::Pli, start + Duration::from_millis(100));
+        throttle.request(KeyframeRequest::Fir, start + Duration::from_millis(200));
+        throttle.request(KeyframeRequest::Pli, start + Duration::from_millis(300));
+
+        assert_eq!(
+            throttle.take_due(start + Duration::from_millis(500)),
+            Some(KeyframeRequest::Fir)
+        );
+    }
+}
@@ -1,40 +1,119 @@
 use std::collections::{HashMap, HashSet};
-use std::convert::From;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use warp::ws::Message;
+use warp::ws::{Message, WebSocket};
 
-use futures::StreamExt;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
 use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
-// use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_remote::TrackRemote;
 
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
 use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtcp::reception_report::ReceptionReport;
+use webrtc::rtcp::transport_feedbacks::transport_layer_nack::TransportLayerNack;
 use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
 use webrtc::Error;
 
-use log::{error, warn, info};
+use log::{debug, error, warn, info};
 
+use crate::avatar::{AvatarMessage, AvatarMessageType, AvatarState};
+use crate::codec::{self, CodecName, CodecPolicy};
+use crate::config;
 use crate::errors::ApplicationError;
+use crate::feedback::{Reception, ReceptionAggregator};
 use crate::data::RoomMember;
+use crate::keyframe::KeyframeCache;
+use crate::protocol::{
+    ActiveSpeakerMessage, ClientMessage, EvictedMessage, ForwardingMessage, IceCandidate,
+    MediaKind, MediaState, MemberRole, PausedTrack, PeerLeftMessage, PinMessage, RosterEntry,
+    RosterMessage, ServerMessage, SessionDescription, SessionMessage, SlotEntry, SlotsMessage,
+    SubscriptionMessage,
+};
+use crate::queue::{QueueReceiver, QueueSender};
+use crate::room::{RoomConfig, RoomRef};
+use crate::session::{DuplicateSessionPolicy, ResumeHandle};
+use crate::speaker::{self, ActiveSpeakerDetector};
+use crate::virtual_track::{self, ForwardingMode, PacketFeed, ReplayingTrack, VirtualTrack};
 
 const TRACK_NAME_PREF: &str = "sfu-track-";
+const WS_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub enum RTCPToPublisher {
     PLI,
+    Fir,
+    /// The reception of the publisher's video aggregated across its subscribers.
+    ReceiverReport(Reception),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum TeardownReason {
+    WebSocketClosed,
+    ConnectionFailed,
+    ConnectionClosed,
+    IdleTimeout,
+    ResumeExpired,
+    SessionTakenOver,
+    /// Any of the queues to the peer has overflowed.
+    SlowConsumer,
+}
+
+impl TeardownReason {
+    /// The WebSocket close code and reason told to an evicted peer.
+    /// The codes are taken from the range reserved for applications.
+    ///
+    pub fn close_code(&self) -> Option<(u16, &'static str)> {
+        match self {
+            TeardownReason::WebSocketClosed => None,
+            TeardownReason::ConnectionFailed => Some((4001, "connection_failed")),
+            TeardownReason::ConnectionClosed => Some((4002, "connection_closed")),
+            TeardownReason::IdleTimeout => Some((4003, "idle_timeout")),
+            TeardownReason::ResumeExpired => None,
+            TeardownReason::SessionTakenOver => Some((4005, "session_taken_over")),
+            TeardownReason::SlowConsumer => Some((4010, "slow_consumer")),
+        }
+    }
+}
+
+/// The side the SFU plays in perfect negotiation when it and the peer make offers at once.
+/// The peer is expected to play the other side.
+///
+/// Reference: https://developer.mozilla.org/en-US/docs/Web/API/WebRTC_API/Perfect_negotiation
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NegotiationRole {
+    /// Rolls back its own offer and answers the peer's.
+    Polite,
+    /// Ignores the peer's offer and waits for the answer to its own.
+    Impolite,
+}
+
+impl FromStr for NegotiationRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "polite" => Ok(NegotiationRole::Polite),
+            "impolite" => Ok(NegotiationRole::Impolite),
+            _ => Err(format!("Unknown negotiation role {:?}.", s)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -42,81 +121,196 @@ pub enum MessageToPublisher {
     RTCP(RTCPToPublisher),
 }
 
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
-pub enum SubscriberMessageType {
-    Prepare,
-    Start,
-    Offer,
-    Answer,
-    IceCandidate,
-    Ping,
-    Pong
-}
 #[derive(Serialize, Debug)]
 pub struct ToSubscriberDataChannelMessage {
     pub from: Uuid,
     pub message: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct SubscriberMessage {
-    pub msg_type: SubscriberMessageType,
-    pub message: String,
+impl ToSubscriberDataChannelMessage {
+    /// Whether the message moves an avatar, which the next move makes obsolete.
+    ///
+    pub fn is_avatar_move(&self) -> bool {
+        AvatarMessage::parse(&self.message).and_then(|m| m.message_type())
+            == Some(AvatarMessageType::Move)
+    }
+}
+
+/// Messages handled in the main event loop of a peer.
+///
+#[derive(Debug, Clone)]
+pub enum SubscriberMessage {
+    /// A message from the peer.
+    Client(ClientMessage),
+    /// A message to send to the peer as it is.
+    Relay(ServerMessage),
+    /// The publishers' tracks may have changed.
+    Start,
+    /// The forwarded tracks may have changed.
+    UpdateForwarding,
+    NegotiationNeeded,
+    RestartIce,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct ClientIceCandidate {
-    pub candidate: Option<String>,
-    #[serde(rename = "spdMid")]
-    pub sdp_mid: Option<String>,
-    #[serde(rename = "sdpMLineIndex")]
-    pub sdp_mline_index: Option<u16>,
-    #[serde(rename = "usernameFragment")]
-    pub username_fragment: Option<String>,
+/// The kinds of the publishers' tracks a subscriber has chosen to receive.
+///
+struct Subscription {
+    default_kinds: HashSet<MediaKind>,
+    kinds_by_publisher: HashMap<Uuid, HashSet<MediaKind>>,
 }
 
-impl ClientIceCandidate {
-    fn to_candidate_init(self) -> RTCIceCandidateInit {
-        RTCIceCandidateInit {
-            candidate: self.candidate.unwrap_or("".to_owned()),
-            sdp_mid: self.sdp_mid.unwrap_or("".to_owned()),
-            sdp_mline_index: self.sdp_mline_index.unwrap_or(0u16),
-            username_fragment: self.username_fragment.unwrap_or("".to_owned()),
+impl Subscription {
+    fn new() -> Self {
+        Subscription {
+            default_kinds: MediaKind::all(),
+            kinds_by_publisher: HashMap::new(),
         }
     }
 
-    fn from(another: RTCIceCandidateInit) -> ClientIceCandidate {
-        ClientIceCandidate {
-            candidate: Some(another.candidate),
-            sdp_mid: Some(another.sdp_mid),
-            sdp_mline_index: Some(another.sdp_mline_index),
-            username_fragment: Some(another.username_fragment),
+    fn update(&mut self, msg: SubscriptionMessage, subscribe: bool) {
+        let kinds: HashSet<MediaKind> = msg
+            .kinds
+            .map(|kinds| kinds.into_iter().collect())
+            .unwrap_or(MediaKind::all());
+
+        let apply = |current: &mut HashSet<MediaKind>| {
+            if subscribe {
+                current.extend(kinds.iter());
+            } else {
+                current.retain(|k| !kinds.contains(k));
+            }
+        };
+
+        match msg.peer_ids {
+            Some(peer_ids) => {
+                for peer_id in peer_ids {
+                    let default_kinds = self.default_kinds.clone();
+                    apply(
+                        self.kinds_by_publisher
+                            .entry(peer_id)
+                            .or_insert(default_kinds),
+                    );
+                }
+            }
+            None => {
+                apply(&mut self.default_kinds);
+                self.kinds_by_publisher.values_mut().for_each(apply);
+            }
         }
     }
+
+    fn includes(&self, pub_id: &Uuid, kind: RTPCodecType) -> bool {
+        let kind = match MediaKind::of(kind) {
+            Some(k) => k,
+            None => return false,
+        };
+        self.kinds_by_publisher
+            .get(pub_id)
+            .unwrap_or(&self.default_kinds)
+            .contains(&kind)
+    }
 }
 
-type ToPublisherChannel = tokio::sync::mpsc::UnboundedSender<MessageToPublisher>;
-type ToSubscriberChannel = tokio::sync::mpsc::UnboundedSender<SubscriberMessage>;
-type ToSubscriberDataChannel = tokio::sync::mpsc::UnboundedSender<ToSubscriberDataChannelMessage>;
+type ToPublisherChannel = QueueSender<MessageToPublisher>;
+type ToSubscriberChannel = QueueSender<SubscriberMessage>;
+type ToSubscriberDataChannel = QueueSender<ToSubscriberDataChannelMessage>;
+type TeardownChannel = tokio::sync::mpsc::UnboundedSender<TeardownReason>;
 
-/// A PeerManager manages the media tracks and channels for communication.
+/// A PeerManager manages the media tracks and channels for communication of the peers in a room.
+///
+/// Each room has its own PeerManager owned by the task of the room, see 'RoomRef'.
 ///
 pub struct PeerManager {
+    room_id: i64,
     tracks: HashMap<Uuid, Vec<Arc<TrackLocalStaticRTP>>>,
-    rooms: HashMap<Uuid, RoomMember>,
+    members: HashMap<Uuid, RoomMember>,
     to_publishers: HashMap<Uuid, ToPublisherChannel>,
     to_subscribers: HashMap<Uuid, ToSubscriberChannel>,
     data_to_subscribers: HashMap<Uuid, ToSubscriberDataChannel>,
+    speakers: ActiveSpeakerDetector,
+    speaker_order: Vec<Uuid>,
+    pins: HashMap<Uuid, HashSet<Uuid>>,
+    subscriptions: HashMap<Uuid, Subscription>,
+    avatars: HashMap<Uuid, AvatarState>,
+    media_states: HashMap<Uuid, MediaState>,
+    resume_handles: HashMap<Uuid, ResumeHandle>,
+    sessions: HashMap<i64, HashMap<Uuid, TeardownChannel>>,
+    packet_feeds: HashMap<Uuid, HashMap<String, PacketFeed>>,
+    decodable_codecs: HashMap<Uuid, HashSet<CodecName>>,
+    receptions: ReceptionAggregator,
+    /// The last-N of the room, read from the row of the first member joining it.
+    last_n: Option<usize>,
+    config: RoomConfig,
 }
 
 impl PeerManager {
-    pub fn new() -> Self {
+    pub fn new(room_id: i64, config: RoomConfig) -> Self {
         PeerManager {
+            room_id,
             tracks: HashMap::new(),
-            rooms: HashMap::new(),
+            members: HashMap::new(),
             to_publishers: HashMap::new(),
             to_subscribers: HashMap::new(),
             data_to_subscribers: HashMap::new(),
+            speakers: ActiveSpeakerDetector::new(),
+            speaker_order: Vec::new(),
+            pins: HashMap::new(),
+            subscriptions: HashMap::new(),
+            avatars: HashMap::new(),
+            media_states: HashMap::new(),
+            resume_handles: HashMap::new(),
+            sessions: HashMap::new(),
+            packet_feeds: HashMap::new(),
+            decodable_codecs: HashMap::new(),
+            receptions: ReceptionAggregator::new(config.receiver_report_interval),
+            last_n: None,
+            config,
+        }
+    }
+
+    /// Applies the duplicate-session policy of the room to a member joining it.
+    ///
+    /// Returns false if the new session must be rejected.
+    /// With 'DuplicateSessionPolicy::Replace', the existing sessions are told to tear down.
+    ///
+    pub fn admit(&self, room_member: &RoomMember) -> bool {
+        let sessions = match self.sessions.get(&room_member.member_id) {
+            Some(sessions) if !sessions.is_empty() => sessions,
+            _ => return true,
+        };
+
+        let policy = self.duplicate_session_policy_of(room_member);
+        info!(
+            "The member {} already has {} session(s) so apply {:?}.",
+            room_member.member_id,
+            sessions.len(),
+            policy
+        );
+        match policy {
+            DuplicateSessionPolicy::Allow => true,
+            DuplicateSessionPolicy::Reject => false,
+            DuplicateSessionPolicy::Replace => {
+                for tx_teardown in sessions.values() {
+                    // The receiver has gone if the teardown has already started.
+                    let _ = tx_teardown.send(TeardownReason::SessionTakenOver);
+                }
+                true
+            }
+        }
+    }
+
+    fn duplicate_session_policy_of(&self, room_member: &RoomMember) -> DuplicateSessionPolicy {
+        match room_member
+            .duplicate_session_policy
+            .as_ref()
+            .map(|p| p.parse())
+        {
+            Some(Ok(policy)) => policy,
+            Some(Err(e)) => {
+                warn!("{} The default policy is used.", e);
+                self.config.default_duplicate_session_policy
+            }
+            None => self.config.default_duplicate_session_policy,
         }
     }
 
@@ -127,16 +321,56 @@ impl PeerManager {
         to_pub_ch: ToPublisherChannel,
         to_sub_ch: ToSubscriberChannel,
         to_sub_data_ch: ToSubscriberDataChannel,
+        teardown_ch: TeardownChannel,
     ) {
-        self.rooms.insert(peer_id.clone(), room_member);
+        self.sessions
+            .entry(room_member.member_id)
+            .or_default()
+            .insert(peer_id.clone(), teardown_ch);
+        if self.last_n.is_none() {
+            self.last_n = Some(
+                room_member
+                    .last_n
+                    .map(|n| n.max(0) as usize)
+                    .unwrap_or(self.config.default_last_n),
+            );
+        }
+        self.members.insert(peer_id.clone(), room_member);
         self.to_publishers.insert(peer_id.clone(), to_pub_ch);
         self.to_subscribers.insert(peer_id.clone(), to_sub_ch);
         self.data_to_subscribers
             .insert(peer_id.clone(), to_sub_data_ch);
+
+        self.send_roster(peer_id);
+        self.send_presence(peer_id, ServerMessage::PeerJoined);
     }
     pub fn add_track(&mut self, peer_id: &Uuid, track: Arc<TrackLocalStaticRTP>) {
-        let tracks = self.tracks.entry(peer_id.clone()).or_insert(Vec::new());
+        let tracks = self.tracks.entry(peer_id.clone()).or_default();
         tracks.push(track);
+        self.send_presence(peer_id, ServerMessage::PeerUpdated);
+
+        if self.members.contains_key(peer_id) && !self.speaker_order.contains(peer_id) {
+            self.speaker_order.push(peer_id.clone());
+        }
+    }
+
+    /// Registers the feed of the packets of a publisher's track for virtual tracks,
+    /// and of its keyframes for the subscribers in either forwarding mode.
+    ///
+    pub fn add_packet_feed(&mut self, peer_id: &Uuid, track_id: &str, feed: PacketFeed) {
+        if self.members.contains_key(peer_id) {
+            self.packet_feeds
+                .entry(peer_id.clone())
+                .or_default()
+                .insert(track_id.to_owned(), feed);
+        }
+    }
+
+    fn packet_feed(&self, peer_id: &Uuid, track_id: &str) -> Option<PacketFeed> {
+        self.packet_feeds
+            .get(peer_id)
+            .and_then(|feeds| feeds.get(track_id))
+            .cloned()
     }
 
     pub fn has_both_audio_and_video(&self, peer_id: &Uuid) -> bool {
@@ -146,63 +380,135 @@ impl PeerManager {
             .unwrap_or(false)
     }
 
-    fn remove_peer(&mut self, peer_id: &Uuid) {
+    /// Removes every state of the peer and notifies the other peers in the room.
+    ///
+    /// Returns false if the peer has already been removed.
+    ///
+    pub fn remove_peer(&mut self, peer_id: &Uuid) -> bool {
+        let member_id = if let Some(room_member) = self.members.get(peer_id) {
+            room_member.member_id
+        } else {
+            return false;
+        };
+
+        if let Some(sessions) = self.sessions.get_mut(&member_id) {
+            sessions.remove(peer_id);
+            if sessions.is_empty() {
+                self.sessions.remove(&member_id);
+            }
+        }
         self.tracks.remove(peer_id);
+        self.packet_feeds.remove(peer_id);
         self.to_publishers.remove(peer_id);
         self.to_subscribers.remove(peer_id);
         self.data_to_subscribers.remove(peer_id);
+        self.pins.remove(peer_id);
+        self.subscriptions.remove(peer_id);
+        self.avatars.remove(peer_id);
+        self.media_states.remove(peer_id);
+        self.resume_handles.remove(peer_id);
+        self.decodable_codecs.remove(peer_id);
+        self.receptions.remove(peer_id);
+        for pins in self.pins.values_mut() {
+            pins.remove(peer_id);
+        }
+        for subscription in self.subscriptions.values_mut() {
+            subscription.kinds_by_publisher.remove(peer_id);
+        }
+
+        self.send_to_others_in_room(
+            peer_id,
+            SubscriberMessage::Relay(ServerMessage::PeerLeft(PeerLeftMessage {
+                peer_id: peer_id.clone(),
+            })),
+        );
+        if self.speakers.remove(peer_id) {
+            // The next dominant speaker is told once detected.
+            self.send_to_others_in_room(
+                peer_id,
+                SubscriberMessage::Relay(ServerMessage::ActiveSpeaker(ActiveSpeakerMessage {
+                    peer_id: None,
+                })),
+            );
+        }
+        self.send_to_subscribers(peer_id, SubscriberMessage::Start);
+        self.members.remove(peer_id);
+
+        self.speaker_order.retain(|id| id != peer_id);
+        true
+    }
+
+    /// Returns whether any state of the peer remains.
+    ///
+    #[cfg(test)]
+    fn holds_state_of(&self, peer_id: &Uuid) -> bool {
+        self.tracks.contains_key(peer_id)
+            || self.packet_feeds.contains_key(peer_id)
+            || self.members.contains_key(peer_id)
+            || self.to_publishers.contains_key(peer_id)
+            || self.to_subscribers.contains_key(peer_id)
+            || self.data_to_subscribers.contains_key(peer_id)
+            || self.pins.contains_key(peer_id)
+            || self.subscriptions.contains_key(peer_id)
+            || self.avatars.contains_key(peer_id)
+            || self.media_states.contains_key(peer_id)
+            || self.resume_handles.contains_key(peer_id)
+            || self.decodable_codecs.contains_key(peer_id)
+            || self.receptions.holds(peer_id)
+            || self.sessions.values().any(|s| s.contains_key(peer_id))
+            || self.pins.values().any(|pins| pins.contains(peer_id))
+            || self
+                .subscriptions
+                .values()
+                .any(|s| s.kinds_by_publisher.contains_key(peer_id))
+            || self.speaker_order.contains(peer_id)
+            || self.speakers.dominant() == Some(*peer_id)
     }
 
     pub fn send_to_subscribers(&self, peer_id: &Uuid, message: SubscriberMessage) {
-        let my_room = if let Some(my_room) = self.rooms.get(peer_id) {
-            my_room
-        } else {
+        if !self.members.contains_key(peer_id) {
             warn!("Peer mapped to {:?} doesn't exit.", peer_id);
             return;
-        };
+        }
         for (sub_id, tx_ch) in self.to_subscribers.iter() {
+            debug!("Send {:?} to subscriber {:?}", message, sub_id);
 
-            let sub_room = if let Some(sub_room) = self.rooms.get(sub_id) {
-                sub_room
-            } else {
-                warn!("Subscriber mapped to {:?} doesn't exit.", sub_id);
-                continue;
-            };
+            if let Err(e) = tx_ch.send(message.clone()) {
+                error!("Error while sending a message to {:?} {:?}", sub_id, e);
+            }
+        }
+    }
 
-            if sub_room.room_id != my_room.room_id {
+    fn send_to_others_in_room(&self, peer_id: &Uuid, message: SubscriberMessage) {
+        if !self.members.contains_key(peer_id) {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return;
+        }
+        for (sub_id, tx_ch) in self.to_subscribers.iter() {
+            if sub_id == peer_id {
                 continue;
             }
 
-            info!("Require renegotiation to subscriber {:?}", sub_id);
+            if let Err(e) = tx_ch.send(message.clone()) {
+                error!("Error while sending a message to {:?} {:?}", sub_id, e);
+            }
+        }
+    }
 
-            if let Err(e) = tx_ch.send(SubscriberMessage {
-                msg_type: message.msg_type,
-                message: message.message.clone(),
-            }) {
+    fn send_to_subscriber(&self, sub_id: &Uuid, message: SubscriberMessage) {
+        if let Some(sender) = self.to_subscribers.get(sub_id) {
+            if let Err(e) = sender.send(message) {
                 error!("Error while sending a message to {:?} {:?}", sub_id, e);
             }
         }
     }
 
     pub fn send_data_to_subscribers(&self, peer_id: &Uuid, message: String) {
-        let my_room = if let Some(my_room) = self.rooms.get(peer_id) {
-            my_room
-        } else {
+        if !self.members.contains_key(peer_id) {
             warn!("Peer mapped to {:?} doesn't exit.", peer_id);
             return;
-        };
+        }
         for (sub_id, tx_ch) in self.data_to_subscribers.iter() {
-            let sub_room = if let Some(sub_room) = self.rooms.get(sub_id) {
-                sub_room
-            } else {
-                warn!("Subscriber mapped to {:?} doesn't exit.", sub_id);
-                continue;
-            };
-
-            if sub_room.room_id != my_room.room_id {
-                continue;
-            }
-
             // info!("Send data to subscriber {:?}", sub_id);
 
             if let Err(e) = tx_ch.send(ToSubscriberDataChannelMessage {
@@ -220,30 +526,24 @@ impl PeerManager {
     ) -> (HashSet<String>, Vec<(Uuid, Arc<TrackLocalStaticRTP>)>) {
         let mut local_tracks = vec![];
         let mut local_track_ids = HashSet::new();
+        if !self.members.contains_key(peer_id) {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return (local_track_ids, local_tracks);
+        }
         for (pub_id, ts) in self.tracks.iter() {
             if peer_id == pub_id {
                 continue;
             }
 
-            let my_room = if let Some(my_room) = self.rooms.get(peer_id) {
-                my_room
-            } else {
-                warn!("Peer mapped to {:?} doesn't exit.", peer_id);
-                continue;
-            };
-
-            let pub_room = if let Some(pub_room) = self.rooms.get(pub_id) {
-                pub_room
-            } else {
-                warn!("Publisher mapped to {:?} doesn't exit.", pub_id);
-                continue;
-            };
-
-            if pub_room.room_id != my_room.room_id {
-                continue;
-            }
-
             for local_track in ts {
+                if let Some(subscription) = self.subscriptions.get(peer_id) {
+                    if !subscription.includes(pub_id, local_track.kind()) {
+                        continue;
+                    }
+                }
+                if !self.can_decode(peer_id, local_track) {
+                    continue;
+                }
                 local_tracks.push((pub_id.clone(), Arc::clone(&local_track)));
                 local_track_ids.insert(local_track.id().to_owned());
             }
@@ -251,6 +551,54 @@ impl PeerManager {
         (local_track_ids, local_tracks)
     }
 
+    /// Records the codecs the peer has told it can decode in its latest description.
+    ///
+    /// Returns whether they have changed, which may change the tracks forwarded to the peer.
+    ///
+    pub fn set_decodable_codecs(&mut self, peer_id: &Uuid, codecs: HashSet<CodecName>) -> bool {
+        if !self.members.contains_key(peer_id) || codecs.is_empty() {
+            return false;
+        }
+        info!("{:?} can decode {:?}.", peer_id, codecs);
+        self.decodable_codecs.insert(peer_id.clone(), codecs.clone()) != Some(codecs)
+    }
+
+    /// Whether the subscriber can decode the track, which is assumed until it has negotiated.
+    ///
+    fn can_decode(&self, sub_id: &Uuid, track: &TrackLocalStaticRTP) -> bool {
+        match (
+            self.decodable_codecs.get(sub_id),
+            CodecName::of(&track.codec()),
+        ) {
+            (Some(codecs), Some(codec)) => codecs.contains(&codec),
+            _ => true,
+        }
+    }
+
+    /// The subscribed tracks that aren't forwarded to the subscriber since it can't decode them.
+    ///
+    fn undecodable_tracks(&self, sub_id: &Uuid) -> Vec<PausedTrack> {
+        let mut undecodable = vec![];
+        for (pub_id, tracks) in self.tracks.iter().filter(|(pub_id, _)| *pub_id != sub_id) {
+            for track in tracks {
+                let subscribed = self
+                    .subscriptions
+                    .get(sub_id)
+                    .map(|subscription| subscription.includes(pub_id, track.kind()))
+                    .unwrap_or(true);
+                if subscribed && !self.can_decode(sub_id, track) {
+                    undecodable.push(PausedTrack {
+                        peer_id: pub_id.clone(),
+                        kind: MediaKind::of(track.kind()),
+                    });
+                }
+            }
+        }
+        // Sorted so that the subscriber is told only when the tracks have changed.
+        undecodable.sort_by_key(|track| (track.peer_id, track.kind == Some(MediaKind::Video)));
+        undecodable
+    }
+
     fn send_to_publisher(&self, pc_id: &Uuid, message: MessageToPublisher) {
         if let Some(sender) = self.to_publishers.get(pc_id) {
             if let Err(e) = sender.send(message) {
@@ -259,13 +607,343 @@ impl PeerManager {
         }
     }
 
+    /// Records the reception a subscriber has reported on a publisher's video
+    /// and relays the aggregated reception to the publisher once per interval.
+    ///
+    pub fn update_reception(&mut self, pub_id: &Uuid, sub_id: &Uuid, report: &ReceptionReport) {
+        if !self.members.contains_key(pub_id) || !self.members.contains_key(sub_id) {
+            return;
+        }
+        if let Some(reception) = self.receptions.update(pub_id, sub_id, report, Instant::now()) {
+            debug!("The video of {:?} is received with {:?}.", pub_id, reception);
+            self.send_to_publisher(
+                pub_id,
+                MessageToPublisher::RTCP(RTCPToPublisher::ReceiverReport(reception)),
+            );
+        }
+    }
+
+    /// Records the audio level of a publisher and notifies the room
+    /// when the dominant speaker changes.
+    ///
+    pub fn update_audio_level(&mut self, peer_id: &Uuid, level: u8) {
+        if !self.members.contains_key(peer_id) {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return;
+        }
+
+        if let Some(speaker_id) = self.speakers.update(peer_id, level, Instant::now()) {
+            info!(
+                "Active speaker has changed to {:?} in room {}.",
+                speaker_id, self.room_id
+            );
+
+            if self.move_to_front_of_speakers(&speaker_id) {
+                self.send_to_subscribers(peer_id, SubscriberMessage::UpdateForwarding);
+            }
+
+            self.send_to_subscribers(
+                peer_id,
+                SubscriberMessage::Relay(ServerMessage::ActiveSpeaker(ActiveSpeakerMessage {
+                    peer_id: Some(speaker_id),
+                })),
+            );
+        }
+    }
+
+    /// Moves the speaker to the front of the room's speaker order
+    /// and returns whether the last-N publishers of the room may have changed.
+    ///
+    fn move_to_front_of_speakers(&mut self, speaker_id: &Uuid) -> bool {
+        let last_n = self.last_n();
+        let order = &mut self.speaker_order;
+        let position = order.iter().position(|id| id == speaker_id);
+        if position == Some(0) {
+            return false;
+        }
+        if let Some(position) = position {
+            order.remove(position);
+        }
+        order.insert(0, speaker_id.clone());
+
+        // A speaker already within the first N was forwarded to every subscriber.
+        last_n > 0 && position.map(|p| p >= last_n).unwrap_or(true)
+    }
+
+    fn last_n(&self) -> usize {
+        self.last_n.unwrap_or(self.config.default_last_n)
+    }
+
+    fn update_subscription(&mut self, sub_id: &Uuid, msg: SubscriptionMessage, subscribe: bool) {
+        self.subscriptions
+            .entry(sub_id.clone())
+            .or_insert(Subscription::new())
+            .update(msg, subscribe);
+    }
+
+    /// Keeps the avatar state carried by a data channel message.
+    ///
+    /// When proximity-based forwarding is enabled,
+    /// the peers whose forwarding is affected by the move are notified.
+    ///
+    pub fn observe_avatar_message(&mut self, peer_id: &Uuid, message: &str) {
+        let avatar_message = match AvatarMessage::parse(message) {
+            Some(m) => m,
+            None => return,
+        };
+
+        let member_name = if let Some(room_member) = self.members.get(peer_id) {
+            room_member.member_name.clone()
+        } else {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return;
+        };
+
+        let others: Vec<Uuid> = self
+            .members
+            .keys()
+            .filter(|id| *id != peer_id)
+            .cloned()
+            .collect();
+        let nearby_before: HashSet<Uuid> = others
+            .iter()
+            .filter(|id| self.in_proximity(peer_id, id))
+            .cloned()
+            .collect();
+
+        // Messages without a position, such as 'Stop' and 'Bye', don't make the avatar known.
+        let is_new = !self.avatars.contains_key(peer_id);
+        if is_new && !avatar_message.carries_position() {
+            return;
+        }
+        let moved = self
+            .avatars
+            .entry(peer_id.clone())
+            .or_insert_with(|| AvatarState::new(avatar_message.id.clone(), member_name))
+            .apply(&avatar_message);
+
+        if self.config.proximity_distance <= 0.0 || !(is_new || moved) {
+            return;
+        }
+
+        let changed: Vec<&Uuid> = others
+            .iter()
+            .filter(|id| nearby_before.contains(*id) != self.in_proximity(peer_id, id))
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+
+        info!(
+            "Peers in proximity of {:?} have changed by {:?}.",
+            peer_id, changed
+        );
+        for sub_id in changed.into_iter().chain(std::iter::once(peer_id)) {
+            self.send_to_subscriber(sub_id, SubscriberMessage::UpdateForwarding);
+        }
+    }
+
+    /// Returns the avatars of the other peers in the room, serialized as 'HelloResponse' messages.
+    ///
+    pub fn avatar_snapshot(&self, peer_id: &Uuid) -> Vec<ToSubscriberDataChannelMessage> {
+        if !self.members.contains_key(peer_id) {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return vec![];
+        }
+
+        let mut snapshot = vec![];
+        for (other_id, avatar) in self.avatars.iter() {
+            if other_id == peer_id {
+                continue;
+            }
+
+            match avatar.to_hello_response() {
+                Ok(message) => snapshot.push(ToSubscriberDataChannelMessage {
+                    from: other_id.clone(),
+                    message,
+                }),
+                Err(e) => error!("{:?} on {:?}.", e, other_id),
+            }
+        }
+        snapshot
+    }
+
+    fn in_proximity(&self, peer_id: &Uuid, another_id: &Uuid) -> bool {
+        let proximity_distance = self.config.proximity_distance;
+        if proximity_distance <= 0.0 {
+            return true;
+        }
+        match (self.avatars.get(peer_id), self.avatars.get(another_id)) {
+            (Some(a), Some(b)) => a.coord().distance(b.coord()) <= proximity_distance,
+            // Peers whose avatars haven't been placed yet are handled as usual.
+            _ => true,
+        }
+    }
+
+    /// Sets the publishers whose videos are always forwarded to the subscriber.
+    ///
+    pub fn set_pins(&mut self, sub_id: &Uuid, pins: HashSet<Uuid>) {
+        self.pins.insert(sub_id.clone(), pins);
+    }
+
+    /// Decides whether the track of the publisher should be forwarded to the subscriber.
+    ///
+    /// Nothing is forwarded between peers whose avatars are farther apart than the proximity
+    /// distance. Otherwise audio is always forwarded, and video is forwarded only from the
+    /// N most recently active speakers and the publishers pinned by the subscriber when
+    /// last-N is enabled.
+    ///
+    pub fn is_forwarded(&self, sub_id: &Uuid, pub_id: &Uuid, kind: RTPCodecType) -> bool {
+        if !self.in_proximity(sub_id, pub_id) {
+            return false;
+        }
+
+        if kind != RTPCodecType::Video {
+            return true;
+        }
+
+        if !self.members.contains_key(sub_id) {
+            return false;
+        }
+
+        let last_n = self.last_n();
+        if last_n == 0 {
+            return true;
+        }
+
+        if self
+            .pins
+            .get(sub_id)
+            .map(|pins| pins.contains(pub_id))
+            .unwrap_or(false)
+        {
+            return true;
+        }
+
+        self.speaker_order
+            .iter()
+            .filter(|id| *id != sub_id)
+            .take(last_n)
+            .any(|id| id == pub_id)
+    }
+
+    fn roster_entry(&self, peer_id: &Uuid) -> Option<RosterEntry> {
+        self.members.get(peer_id).map(|room_member| RosterEntry {
+            peer_id: peer_id.clone(),
+            member_id: room_member.member_id,
+            member_name: room_member.member_name.clone(),
+            role: MemberRole::Member,
+            published_kinds: self
+                .tracks
+                .get(peer_id)
+                .map(|ts| ts.iter().filter_map(|t| MediaKind::of(t.kind())).collect())
+                .unwrap_or(vec![]),
+            media_state: self.media_states.get(peer_id).cloned().unwrap_or_default(),
+        })
+    }
+
+    /// Sends the peers in the room to the peer.
+    ///
+    fn send_roster(&self, peer_id: &Uuid) {
+        if !self.members.contains_key(peer_id) {
+            warn!("Peer mapped to {:?} doesn't exit.", peer_id);
+            return;
+        }
+
+        let members = self
+            .members
+            .keys()
+            .filter_map(|id| self.roster_entry(id))
+            .collect();
+
+        self.send_to_subscriber(
+            peer_id,
+            SubscriberMessage::Relay(ServerMessage::Roster(RosterMessage {
+                peer_id: peer_id.clone(),
+                members,
+            })),
+        );
+
+        // The peer is told the active speaker only when it changes, so the current one
+        // follows the roster.
+        if let Some(speaker_id) = self.speakers.dominant() {
+            self.send_to_subscriber(
+                peer_id,
+                SubscriberMessage::Relay(ServerMessage::ActiveSpeaker(ActiveSpeakerMessage {
+                    peer_id: Some(speaker_id),
+                })),
+            );
+        }
+    }
+
+    /// Notifies the other peers in the room of the peer's current state.
+    ///
+    fn send_presence(&self, peer_id: &Uuid, message: fn(RosterEntry) -> ServerMessage) {
+        if let Some(entry) = self.roster_entry(peer_id) {
+            self.send_to_others_in_room(peer_id, SubscriberMessage::Relay(message(entry)));
+        }
+    }
+
+    /// Asks a peer that has come back from a stale state to resync,
+    /// then sends the current roster and renegotiates its tracks.
+    ///
+    pub fn resync(&self, peer_id: &Uuid) {
+        self.send_to_subscriber(peer_id, SubscriberMessage::Relay(ServerMessage::Resync));
+        self.send_roster(peer_id);
+        self.send_to_subscriber(peer_id, SubscriberMessage::Start);
+    }
+
+    /// Makes the session of the peer resumable and tells the peer its resume token.
+    ///
+    pub fn set_resume_handle(&mut self, peer_id: &Uuid, handle: ResumeHandle) {
+        self.send_to_subscriber(
+            peer_id,
+            SubscriberMessage::Relay(ServerMessage::Session(SessionMessage {
+                peer_id: peer_id.clone(),
+                resume_token: handle.token().to_owned(),
+                resume_grace_period_secs: handle.grace_period().as_secs(),
+            })),
+        );
+        self.resume_handles.insert(peer_id.clone(), handle);
+    }
+
+    /// Attaches a reconnected WebSocket to the session the resume token has been issued for.
+    ///
+    /// Returns the id of the resumed peer, or gives the WebSocket back if there is no such session.
+    ///
+    pub fn attach_to_session(
+        &self,
+        resume_token: &str,
+        member_id: i64,
+        mut ws: Box<WebSocket>,
+    ) -> Result<Uuid, Box<WebSocket>> {
+        for (peer_id, handle) in self.resume_handles.iter() {
+            ws = match handle.attach(resume_token, member_id, ws) {
+                Ok(()) => return Ok(peer_id.clone()),
+                Err(ws) => ws,
+            };
+        }
+        Err(ws)
+    }
+
+    /// Restarts ICE on a peer that has resumed its session on a new WebSocket
+    /// and sends the current roster, which may have changed while the peer was away.
+    ///
+    pub fn resume(&self, peer_id: &Uuid) {
+        self.send_to_subscriber(peer_id, SubscriberMessage::RestartIce);
+        self.send_roster(peer_id);
+    }
+
+    fn update_media_state(&mut self, peer_id: &Uuid, media_state: MediaState) {
+        self.media_states.insert(peer_id.clone(), media_state);
+        self.send_presence(peer_id, ServerMessage::PeerUpdated);
+    }
+
     pub fn get_name_by_peer_id(&self, peer_id: &Uuid) -> Option<String> {
-        self.rooms.get(peer_id).map(|r| r.member_name.clone())
+        self.members.get(peer_id).map(|r| r.member_name.clone())
     }
 }
 
-pub type PeerManagerRef = Arc<Mutex<PeerManager>>;
-
 /// Handles 'track' events on RTCPeerConnection.
 ///
 pub fn on_track(
@@ -273,6 +951,7 @@ pub fn on_track(
     track: Option<Arc<TrackRemote>>,
     track_ssrc_tx: Arc<tokio::sync::mpsc::Sender<u32>>,
     local_track_chan_tx: Arc<tokio::sync::mpsc::Sender<Arc<TrackLocalStaticRTP>>>,
+    room: RoomRef,
 ) {
     let peer_id = peer_id.clone();
     if let Some(track) = track {
@@ -301,9 +980,38 @@ pub fn on_track(
                 format!("sfu-stream-{:?}", peer_id),
             ));
 
+            // Virtual tracks of subscribers take the packets from the feed, and subscribers
+            // in either mode start with the latest keyframe of the video cached in it.
+            let max_age =
+                Duration::from_millis(config::env_or("KEYFRAME_CACHE_MAX_AGE_MILLIS", 3000));
+            let keyframe_cache = CodecName::of(&local_track.codec())
+                .and_then(|codec| KeyframeCache::new(codec, max_age));
+            let packet_feed = PacketFeed::new(keyframe_cache);
+            let track_id = local_track.id().to_owned();
+            let feed = packet_feed.clone();
+            room.tell(move |peer_manager| {
+                peer_manager.add_packet_feed(&peer_id, &track_id, feed);
+            });
+
             let _ = local_track_chan_tx2.send(Arc::clone(&local_track)).await;
 
+            let mut audio_level_meter = if track.kind() == RTPCodecType::Audio {
+                AudioLevelMeter::new(&track).await
+            } else {
+                None
+            };
+
             while let Ok((rtp, _)) = track.read_rtp().await {
+                if let Some(meter) = audio_level_meter.as_mut() {
+                    if let Some(level) = meter.measure(&rtp) {
+                        room.tell(move |peer_manager| {
+                            peer_manager.update_audio_level(&peer_id, level);
+                        });
+                    }
+                }
+
+                packet_feed.send(&rtp);
+
                 if let Err(e) = local_track.write_rtp(&rtp).await {
                     if Error::ErrClosedPipe != e {
                         error!(
@@ -320,34 +1028,147 @@ pub fn on_track(
     }
 }
 
+/// Averages the levels carried by the 'ssrc-audio-level' header extension
+/// of an audio track over each report interval.
+///
+struct AudioLevelMeter {
+    extension_id: u8,
+    level_sum: u32,
+    count: u32,
+    reported_at: Instant,
+}
+
+impl AudioLevelMeter {
+    async fn new(track: &TrackRemote) -> Option<AudioLevelMeter> {
+        track
+            .params()
+            .await
+            .header_extensions
+            .iter()
+            .find(|ext| ext.uri == speaker::AUDIO_LEVEL_URI)
+            .map(|ext| AudioLevelMeter {
+                extension_id: ext.id as u8,
+                level_sum: 0,
+                count: 0,
+                reported_at: Instant::now(),
+            })
+    }
+
+    fn measure(&mut self, rtp: &webrtc::rtp::packet::Packet) -> Option<u8> {
+        if let Some(level) = rtp
+            .header
+            .get_extension(self.extension_id)
+            .and_then(|payload| speaker::parse_audio_level(&payload))
+        {
+            self.level_sum += level as u32;
+            self.count += 1;
+        }
+
+        if self.count == 0 || self.reported_at.elapsed() < speaker::AUDIO_LEVEL_REPORT_INTERVAL {
+            return None;
+        }
+
+        let level = (self.level_sum / self.count) as u8;
+        self.level_sum = 0;
+        self.count = 0;
+        self.reported_at = Instant::now();
+        Some(level)
+    }
+}
+
 /// Handles 'connection_state_change' events on RTCPeerConnection.
 ///
+/// 'Disconnected' may recover by itself, so only 'Failed' and 'Closed' are reported.
+/// The session decides whether to restart ICE or tear down the peer.
+///
 pub fn on_peer_connection_state_change(
     state: RTCPeerConnectionState,
     peer_id: &Uuid,
-    peer_manager: PeerManagerRef,
+    tx_teardown: UnboundedSender<TeardownReason>,
 ) {
     info!(
         "Peer connection state has changed to {} on {:?}.",
         state, peer_id
     );
 
-    if state == RTCPeerConnectionState::Disconnected {
-        let mut peer_manager = peer_manager.lock().unwrap();
-        peer_manager.remove_peer(&peer_id);
-        peer_manager.send_to_subscribers(&peer_id, SubscriberMessage {
-            msg_type: SubscriberMessageType::Start,
-            message: String::from(""),
+    let reason = match state {
+        RTCPeerConnectionState::Failed => TeardownReason::ConnectionFailed,
+        RTCPeerConnectionState::Closed => TeardownReason::ConnectionClosed,
+        _ => return,
+    };
+
+    // The receiver has gone if the teardown has already started.
+    let _ = tx_teardown.send(reason);
+}
+
+/// Tears down a peer: removes its state, notifies the room, tells the peer why it is evicted,
+/// stops its tasks and closes its RTCPeerConnection.
+///
+/// This is the only path through which a peer leaves.
+/// 'ws_task' must be the task writing to the WebSocket, which ends after sending a close frame.
+///
+pub async fn teardown_peer(
+    peer_id: &Uuid,
+    reason: TeardownReason,
+    pc: Arc<RTCPeerConnection>,
+    room: RoomRef,
+    tx_ws: QueueSender<warp::ws::Message>,
+    mut ws_task: JoinHandle<()>,
+    tasks: Vec<JoinHandle<()>>,
+) {
+    info!("Tear down {:?} because of {:?}.", peer_id, reason);
+
+    let removed_id = peer_id.clone();
+    let removed = room
+        .ask(move |peer_manager| peer_manager.remove_peer(&removed_id))
+        .await;
+    if removed != Some(true) {
+        warn!("{:?} has already been removed.", peer_id);
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+
+    if let Some((code, close_reason)) = reason.close_code() {
+        // The messages a slow consumer hasn't kept up with would leave no room for the close frame.
+        if matches!(reason, TeardownReason::SlowConsumer) {
+            let cleared = tx_ws.clear();
+            debug!("Drop {} messages queued to {:?}.", cleared, peer_id);
+        }
+        let message = ServerMessage::Evicted(EvictedMessage {
+            code,
+            reason: close_reason.to_owned(),
         });
+        if let Err(e) = send_to_peer(&message, &tx_ws) {
+            error!("{:?} on {:?}.", e, peer_id);
+        }
+        if let Err(e) = tx_ws.send(Message::close_with(code, close_reason)) {
+            error!("{:?} on {:?}.", e, peer_id);
+        }
+        if tokio::time::timeout(WS_CLOSE_TIMEOUT, &mut ws_task)
+            .await
+            .is_err()
+        {
+            ws_task.abort();
+        }
+    } else {
+        ws_task.abort();
+    }
+
+    if let Err(e) = pc.close().await {
+        error!("{:?} on {:?}.", e, peer_id);
     }
 }
 
 /// Handles 'negotiation_needed' events on RTCPeerConnection.
 ///
+/// The offer is made by the event loop of the peer so that it never overlaps other negotiations.
+///
 pub fn on_negotiation_needed(
     peer_id: &Uuid,
     peer_connection: Arc<RTCPeerConnection>,
-    tx_ws: UnboundedSender<warp::ws::Message>,
+    tx_main: QueueSender<SubscriberMessage>,
 ) {
     info!(
         "Negotiation has been needed on {:?} - {:?}.",
@@ -355,12 +1176,9 @@ pub fn on_negotiation_needed(
         peer_connection.signaling_state()
     );
 
-    let peer_id = peer_id.clone();
-    tokio::spawn(async move {
-        if let Err(e) = do_offer(peer_connection, tx_ws).await {
-            error!("{:?} on {:?}.", e, peer_id);
-        }
-    });
+    if let Err(e) = tx_main.send(SubscriberMessage::NegotiationNeeded) {
+        error!("{:?} on {:?}.", e, peer_id);
+    }
 }
 
 /// Handles 'ice_candidate' events on RTCPeerConnection.
@@ -368,7 +1186,7 @@ pub fn on_negotiation_needed(
 pub fn on_ice_candidate(
     peer_id: &Uuid,
     candidate: RTCIceCandidate,
-    tx_ws: UnboundedSender<warp::ws::Message>,
+    tx_ws: QueueSender<warp::ws::Message>,
 ) {
     let tx_ws_facade_for_ice_candidate = tx_ws.clone();
     let peer_id = peer_id.clone();
@@ -381,26 +1199,8 @@ pub fn on_ice_candidate(
             }
         };
 
-        let candidate_str = match serde_json::to_string(&ClientIceCandidate::from(candidate_json)) {
-            Ok(c) => c,
-            Err(e) => {
-                error!("{:?} on {:?}.", e, peer_id);
-                return;
-            }
-        };
-
-        let ret_message = match serde_json::to_string(&SubscriberMessage {
-            msg_type: SubscriberMessageType::IceCandidate,
-            message: candidate_str,
-        }) {
-            Ok(m) => m,
-            Err(e) => {
-                error!("{:?} on {:?}.", e, peer_id);
-                return;
-            }
-        };
-
-        if let Err(e) = tx_ws_facade_for_ice_candidate.send(Message::text(ret_message)) {
+        let message = ServerMessage::IceCandidate(IceCandidate::from(candidate_json));
+        if let Err(e) = send_to_peer(&message, &tx_ws_facade_for_ice_candidate) {
             error!("{:?} on {:?}.", e, peer_id);
         }
     });
@@ -411,9 +1211,32 @@ pub fn on_ice_candidate(
 pub async fn on_data_channel_open(
     peer_id: &Uuid,
     data_ch_to_send: Arc<RTCDataChannel>,
-    mut rx_data: UnboundedReceiverStream<ToSubscriberDataChannelMessage>,
+    mut rx_data: QueueReceiver<ToSubscriberDataChannelMessage>,
+    room: RoomRef,
 ) {
-    while let Some(msg) = rx_data.next().await {
+    // Sends the avatars already in the room so as not to wait for their responses to 'Hello'.
+    let snapshot_id = peer_id.clone();
+    let snapshot = room
+        .ask(move |peer_manager| peer_manager.avatar_snapshot(&snapshot_id))
+        .await
+        .unwrap_or_default();
+    info!(
+        "Send the snapshot of {} avatars to {:?}.",
+        snapshot.len(),
+        peer_id
+    );
+    for msg in snapshot {
+        match serde_json::to_string(&msg) {
+            Ok(msg_str) => {
+                if let Err(e) = data_ch_to_send.send_text(msg_str).await {
+                    error!("{:?} on {:?}.", e, peer_id);
+                }
+            }
+            Err(e) => error!("{:?} on {:?}.", e, peer_id),
+        }
+    }
+
+    while let Some(msg) = rx_data.recv().await {
         if &msg.from == peer_id {
             continue;
         }
@@ -434,21 +1257,20 @@ pub async fn on_data_channel_open(
 ///
 pub async fn handle_ice_candidate_message(
     peer_id: &Uuid,
-    msg: &SubscriberMessage,
+    candidate: Option<IceCandidate>,
     pc: Arc<RTCPeerConnection>,
 ) -> Result<(), ApplicationError> {
-    if &msg.message == "null" {
-        return Ok(());
-    }
+    let candidate = match candidate {
+        Some(candidate) => candidate,
+        None => return Ok(()),
+    };
 
     info!(
-        "An ICE candidate has been received on {:?} {}.",
-        peer_id, &msg.message
+        "An ICE candidate has been received on {:?} {:?}.",
+        peer_id, candidate
     );
 
-    let ice_candidate =
-        serde_json::from_str::<ClientIceCandidate>(&msg.message)?.to_candidate_init();
-    pc.add_ice_candidate(ice_candidate).await?;
+    pc.add_ice_candidate(candidate.to_candidate_init()).await?;
 
     Ok(())
 }
@@ -457,135 +1279,1056 @@ pub async fn handle_ice_candidate_message(
 ///
 pub async fn handle_answer_message(
     peer_id: &Uuid,
-    msg: &SubscriberMessage,
+    answer: SessionDescription,
     pc: Arc<RTCPeerConnection>,
+    room: &RoomRef,
+    renegotiation: &mut Renegotiation,
 ) -> Result<(), ApplicationError> {
     info!("Receive answer on {:?}.", peer_id);
 
-    let answer = serde_json::from_str::<RTCSessionDescription>(&msg.message)?;
-    pc.set_remote_description(answer).await?;
+    // The offer may have been rolled back because of a collision with the peer's offer.
+    if pc.signaling_state() != RTCSignalingState::HaveLocalOffer {
+        warn!(
+            "Ignore the answer to no offer on {:?} - {:?}.",
+            peer_id,
+            pc.signaling_state()
+        );
+        return Ok(());
+    }
+
+    let decodable = codec::decodable_codecs(&answer.sdp);
+    pc.set_remote_description(answer.into_rtc()?).await?;
+    renegotiation.on_answered();
+    update_decodable_codecs(peer_id, decodable, room, renegotiation).await;
 
     Ok(())
 }
 
-/// Handles 'Start' messages.
+/// Handles 'Offer' messages from the peer.
+///
+/// When the offer collides with the SFU's own one, the polite SFU rolls back its offer
+/// and offers again after answering, while the impolite SFU ignores the peer's offer.
 ///
-pub async fn handle_start_message(
+pub async fn handle_offer_message(
     peer_id: &Uuid,
+    offer: SessionDescription,
     pc: Arc<RTCPeerConnection>,
-    peer_manager: PeerManagerRef,
-    tx_ws: UnboundedSender<warp::ws::Message>,
+    room: &RoomRef,
+    tx_ws: QueueSender<warp::ws::Message>,
+    role: NegotiationRole,
+    renegotiation: &mut Renegotiation,
 ) -> Result<(), ApplicationError> {
-    info!("Prepare tracks on {:?}.", peer_id);
-    let peer_id = peer_id.clone();
-    let local_track_ids;
-    let local_tracks;
+    info!("Receive offer on {:?}.", peer_id);
 
-    {
-        let peer_manager = peer_manager.lock().unwrap();
-        let (ids, tracks) = peer_manager.publisher_tracks_info(&peer_id);
-        local_track_ids = ids;
-        local_tracks = tracks;
+    let decodable = codec::decodable_codecs(&offer.sdp);
+    let offer = offer.into_rtc()?;
+
+    let collision = pc.signaling_state() != RTCSignalingState::Stable;
+    if collision && role == NegotiationRole::Impolite {
+        info!(
+            "Ignore the offer colliding with the SFU's one on {:?}.",
+            peer_id
+        );
+        return Ok(());
+    }
+    if collision {
+        info!(
+            "Roll back the offer colliding with the peer's one on {:?}.",
+            peer_id
+        );
+        rollback(&pc).await?;
+    }
+
+    pc.set_remote_description(offer).await?;
+    let answer = pc.create_answer(None).await?;
+    pc.set_local_description(answer).await?;
+
+    if let Some(local_description) = pc.local_description().await {
+        let answer = SessionDescription::from_rtc(&local_description)?;
+        send_to_peer(&ServerMessage::Answer(answer), &tx_ws)?;
     }
 
-    let mut existing_track_ids = HashSet::new();
-    let senders = pc.get_senders().await;
+    if collision {
+        renegotiation.request_offer(false);
+    }
+    update_decodable_codecs(peer_id, decodable, room, renegotiation).await;
 
-    for sender in senders {
-        if let Some(t) = sender.track().await {
-            let track_id = t.id().to_owned();
+    Ok(())
+}
 
-            if track_id.find(TRACK_NAME_PREF).unwrap_or(1) != 0 {
-                continue;
+/// Records the codecs a peer can decode and syncs its tracks when they have changed,
+/// so that tracks in codecs it can't decode are left out.
+///
+async fn update_decodable_codecs(
+    peer_id: &Uuid,
+    codecs: HashSet<CodecName>,
+    room: &RoomRef,
+    renegotiation: &mut Renegotiation,
+) {
+    let subscriber_id = peer_id.clone();
+    let changed = room
+        .ask(move |peer_manager| peer_manager.set_decodable_codecs(&subscriber_id, codecs))
+        .await
+        .unwrap_or(false);
+    if changed {
+        renegotiation.request_sync();
+    }
+}
+
+/// The sender slots of a subscriber's RTCPeerConnection.
+///
+/// A slot left by a publisher is reused for another publisher's track of the same kind,
+/// so the SDP doesn't grow with every join and leave.
+///
+/// With 'ForwardingMode::Virtual', the slots are virtual tracks all added in the first offer,
+/// and only the forwarded publishers occupy them.
+///
+pub struct SubscriberSenders {
+    slots: Vec<SenderSlot>,
+    mode: ForwardingMode,
+    max_slots_per_kind: usize,
+    codec_policy: CodecPolicy,
+    /// The publishers' tracks left out since the subscriber can't decode their codecs.
+    undecodable: Vec<PausedTrack>,
+}
+
+struct SenderSlot {
+    kind: RTPCodecType,
+    sender: Arc<RTCRtpSender>,
+    mid: Option<String>,
+    /// Shared with the task reading RTCP packets so that they reach the current publisher.
+    publisher_id: Arc<Mutex<Option<Uuid>>>,
+    occupant: Option<ForwardedTrack>,
+    virtual_track: Option<VirtualTrack>,
+}
+
+struct ForwardedTrack {
+    publisher_id: Uuid,
+    track: Arc<TrackLocalStaticRTP>,
+    /// The packets of the track, led by the latest keyframe when the track is attached.
+    feed: Option<PacketFeed>,
+    paused: bool,
+}
+
+#[derive(Default)]
+struct SyncResult {
+    slots_added: bool,
+    slots_removed: bool,
+    slots_changed: bool,
+    undecodable_changed: bool,
+}
+
+impl SubscriberSenders {
+    pub fn new(codec_policy: CodecPolicy) -> Self {
+        SubscriberSenders {
+            slots: vec![],
+            mode: config::env_or("FORWARDING_MODE", ForwardingMode::Tracks),
+            max_slots_per_kind: config::env_or("MAX_SENDER_SLOTS", 16),
+            codec_policy,
+            undecodable: vec![],
+        }
+    }
+
+    /// Adds the virtual tracks on the first call in 'ForwardingMode::Virtual'.
+    ///
+    /// Returns whether they have been added, which needs an offer.
+    ///
+    async fn add_virtual_slots(
+        &mut self,
+        peer_id: &Uuid,
+        pc: &RTCPeerConnection,
+        room: &RoomRef,
+    ) -> Result<bool, ApplicationError> {
+        if self.mode != ForwardingMode::Virtual || !self.slots.is_empty() {
+            return Ok(false);
+        }
+
+        let counts = [
+            (RTPCodecType::Audio, "VIRTUAL_AUDIO_SLOTS"),
+            (RTPCodecType::Video, "VIRTUAL_VIDEO_SLOTS"),
+        ];
+        for (kind, key) in counts {
+            let codec = match virtual_track::codec_of(&self.codec_policy, kind) {
+                Some(codec) => codec,
+                None => {
+                    warn!(
+                        "The room allows no {:?} codec for the virtual tracks of {:?}.",
+                        kind, peer_id
+                    );
+                    continue;
+                }
+            };
+            for _ in 0..config::env_or(key, 4usize) {
+                let virtual_track = VirtualTrack::new(peer_id, kind, codec.clone());
+                let track = virtual_track.track() as Arc<dyn TrackLocal + Send + Sync>;
+                let rtp_sender = pc.add_track(track).await?;
+                let slot =
+                    SenderSlot::new(peer_id, kind, rtp_sender, Some(virtual_track), room.clone());
+                self.slots.push(slot);
             }
+        }
+        info!("Add {} virtual tracks to {:?}.", self.slots.len(), peer_id);
+
+        Ok(true)
+    }
 
-            if !local_track_ids.contains(&track_id) {
-                info!("Remove the track {:?} from {:?}", track_id, peer_id);
-                if let Err(e) = pc.remove_track(&sender).await {
-                    error!("Error while removing track {:?} {:?}.", track_id, e);
+    fn holds(&self, track_id: &str) -> bool {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.occupant.as_ref())
+            .any(|occupant| occupant.track.id() == track_id)
+    }
+
+    /// Finds the mids of the slots, which are known only after their transceivers are negotiated.
+    ///
+    async fn resolve_mids(&mut self, pc: &RTCPeerConnection) {
+        if self.slots.iter().all(|slot| slot.mid.is_some()) {
+            return;
+        }
+        for transceiver in pc.get_transceivers().await {
+            let sender = match transceiver.sender().await {
+                Some(sender) => sender,
+                None => continue,
+            };
+            if let Some(slot) = self
+                .slots
+                .iter_mut()
+                .find(|slot| slot.mid.is_none() && Arc::ptr_eq(&slot.sender, &sender))
+            {
+                let mid = transceiver.mid().await;
+                if !mid.is_empty() {
+                    slot.mid = Some(mid);
                 }
             }
-            existing_track_ids.insert(track_id);
         }
     }
-    info!(
-        "The number of publisher's tracks is {}. Existing track track_ids are {:?} on {:?}.",
-        local_tracks.len(),
-        existing_track_ids,
-        peer_id
-    );
 
-    if local_tracks.len() == 0 {
-        info!("No publisher for {:?}", peer_id);
+    /// Tells the subscriber which publisher currently occupies each slot.
+    ///
+    fn send_slots(&self, tx_ws: &QueueSender<warp::ws::Message>) -> Result<(), ApplicationError> {
+        let slots = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                slot.mid.as_ref().map(|mid| SlotEntry {
+                    mid: mid.clone(),
+                    kind: MediaKind::of(slot.kind),
+                    peer_id: slot.occupant.as_ref().map(|o| o.publisher_id.clone()),
+                })
+            })
+            .collect();
+
+        send_to_peer(&ServerMessage::Slots(SlotsMessage { slots }), tx_ws)
+    }
+}
+
+impl SenderSlot {
+    fn new(
+        peer_id: &Uuid,
+        kind: RTPCodecType,
+        sender: Arc<RTCRtpSender>,
+        virtual_track: Option<VirtualTrack>,
+        room: RoomRef,
+    ) -> Self {
+        let publisher_id = Arc::new(Mutex::new(None));
+        spawn_rtcp_reader(
+            peer_id,
+            kind,
+            Arc::clone(&sender),
+            publisher_id.clone(),
+            virtual_track.is_some(),
+            room,
+        );
+
+        SenderSlot {
+            kind,
+            sender,
+            mid: None,
+            publisher_id,
+            occupant: None,
+            virtual_track,
+        }
+    }
+
+    fn occupy(
+        &mut self,
+        publisher_id: &Uuid,
+        track: Arc<TrackLocalStaticRTP>,
+        feed: Option<PacketFeed>,
+    ) {
+        *self.publisher_id.lock().unwrap() = Some(publisher_id.clone());
+        self.occupant = Some(ForwardedTrack {
+            publisher_id: publisher_id.clone(),
+            track,
+            feed,
+            paused: false,
+        });
+    }
+
+    fn vacate(&mut self) {
+        *self.publisher_id.lock().unwrap() = None;
+        self.occupant = None;
+    }
+}
+
+/// Relays the RTCP packets a subscriber sends through a slot to the publisher occupying it.
+///
+/// PLI and FIR are relayed as they are, and receiver reports on video are aggregated
+/// across the publisher's subscribers, without the sequence numbers and the packets lost
+/// if the slot rewrites the sequence numbers. NACKs are answered by the NACK responder
+/// out of the packets recently sent through the slot, so they never reach the publisher.
+///
+fn spawn_rtcp_reader(
+    peer_id: &Uuid,
+    kind: RTPCodecType,
+    sender: Arc<RTCRtpSender>,
+    publisher_id: Arc<Mutex<Option<Uuid>>>,
+    rewrites_sequence: bool,
+    room: RoomRef,
+) {
+    let peer_id = peer_id.clone();
+    tokio::spawn(async move {
+        // The SSRC of the slot stays the same whichever publisher's track it carries.
+        let ssrc = sender
+            .get_parameters()
+            .await
+            .encodings
+            .first()
+            .map(|encoding| encoding.ssrc);
+        let mut rtcp_buf = vec![0u8; 1500];
+        while let Ok((n, _)) = sender.read(&mut rtcp_buf).await {
+            let mut buf = &rtcp_buf[..n];
+            let publisher_id = match *publisher_id.lock().unwrap() {
+                Some(publisher_id) => publisher_id,
+                None => continue,
+            };
+            // https://stackoverflow.com/questions/33687447/how-to-get-a-reference-to-a-concrete-type-from-a-trait-object
+            if let Ok(packets) = webrtc::rtcp::packet::unmarshal(&mut buf) {
+                for packet in packets {
+                    let packet = packet.as_any();
+                    if let Some(pli_packet) = packet.downcast_ref::<PictureLossIndication>() {
+                        info!("{:?} on {:?}", pli_packet, peer_id);
+                        room.tell(move |peer_manager| {
+                            peer_manager.send_to_publisher(
+                                &publisher_id,
+                                MessageToPublisher::RTCP(RTCPToPublisher::PLI),
+                            );
+                        });
+                    } else if let Some(fir_packet) = packet.downcast_ref::<FullIntraRequest>() {
+                        info!("{:?} on {:?}", fir_packet, peer_id);
+                        room.tell(move |peer_manager| {
+                            peer_manager.send_to_publisher(
+                                &publisher_id,
+                                MessageToPublisher::RTCP(RTCPToPublisher::Fir),
+                            );
+                        });
+                    } else if let Some(nack_packet) = packet.downcast_ref::<TransportLayerNack>() {
+                        debug!(
+                            "{:?} has lost {} packets of {:?}.",
+                            peer_id,
+                            nack_packet.nacks.iter().map(|n| n.packet_list().len()).sum::<usize>(),
+                            publisher_id
+                        );
+                    } else if let Some(rr_packet) = packet.downcast_ref::<ReceiverReport>() {
+                        if kind != RTPCodecType::Video {
+                            continue;
+                        }
+                        // A report may carry blocks on the other streams the subscriber receives.
+                        let report = rr_packet
+                            .reports
+                            .iter()
+                            .find(|report| Some(report.ssrc) == ssrc)
+                            .cloned();
+                        if let Some(mut report) = report {
+                            // They count the packets of a virtual track across the publishers
+                            // it has forwarded, which mean nothing to this publisher.
+                            if rewrites_sequence {
+                                report.total_lost = 0;
+                                report.last_sequence_number = 0;
+                            }
+                            room.tell(move |peer_manager| {
+                                peer_manager.update_reception(&publisher_id, &peer_id, &report);
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Batches the renegotiations requested for a subscriber into as few offers as possible.
+///
+/// Requests within the debounce window are made in one offer,
+/// and no offer is made while another one is waiting for its answer.
+/// Requests postponed by an offer in flight are made once it is answered.
+///
+pub struct Renegotiation {
+    window: Duration,
+    due_at: Option<tokio::time::Instant>,
+    sync_tracks: bool,
+    offer: bool,
+    ice_restart: bool,
+}
+
+impl Renegotiation {
+    pub fn new(window: Duration) -> Self {
+        Renegotiation {
+            window,
+            due_at: None,
+            sync_tracks: false,
+            offer: false,
+            ice_restart: false,
+        }
+    }
+
+    /// Requests to sync the forwarded tracks with the publishers',
+    /// which needs an offer only if they have changed.
+    ///
+    pub fn request_sync(&mut self) {
+        self.sync_tracks = true;
+        self.schedule();
+    }
+
+    pub fn request_offer(&mut self, ice_restart: bool) {
+        self.offer = true;
+        self.ice_restart |= ice_restart;
+        self.schedule();
+    }
+
+    /// The time at which the requested renegotiation is due, if any.
+    ///
+    pub fn due_at(&self) -> Option<tokio::time::Instant> {
+        self.due_at
+    }
+
+    /// Runs the requested renegotiation right away once the offer in flight is answered.
+    ///
+    pub fn on_answered(&mut self) {
+        if self.sync_tracks || self.offer || self.ice_restart {
+            self.due_at = Some(tokio::time::Instant::now());
+        }
+    }
+
+    fn schedule(&mut self) {
+        if self.due_at.is_none() {
+            self.due_at = Some(tokio::time::Instant::now() + self.window);
+        }
+    }
+
+    /// Takes the requests unless an offer is in flight, in which case they are kept
+    /// until the offer is answered.
+    ///
+    fn take(&mut self, signaling_state: RTCSignalingState) -> Option<(bool, bool, bool)> {
+        if signaling_state != RTCSignalingState::Stable {
+            self.due_at = None;
+            return None;
+        }
+        self.due_at = None;
+        Some((
+            std::mem::take(&mut self.sync_tracks),
+            std::mem::take(&mut self.offer),
+            std::mem::take(&mut self.ice_restart),
+        ))
+    }
+}
+
+/// Makes the renegotiation requested for a subscriber if it is due.
+///
+pub async fn renegotiate(
+    peer_id: &Uuid,
+    pc: Arc<RTCPeerConnection>,
+    room: RoomRef,
+    tx_ws: QueueSender<warp::ws::Message>,
+    senders: &mut SubscriberSenders,
+    renegotiation: &mut Renegotiation,
+) -> Result<(), ApplicationError> {
+    let (sync, offer, ice_restart) = match renegotiation.take(pc.signaling_state()) {
+        Some(requests) => requests,
+        None => {
+            debug!(
+                "Postpone renegotiation until the offer is answered on {:?}.",
+                peer_id
+            );
+            return Ok(());
+        }
+    };
+
+    let virtual_slots_added = senders.add_virtual_slots(peer_id, &pc, &room).await?;
+    let mut synced = if sync {
+        sync_tracks(peer_id, &pc, &room, senders).await?
+    } else {
+        SyncResult::default()
+    };
+    synced.slots_added |= virtual_slots_added;
+    if !synced.slots_added
+        && !synced.slots_removed
+        && !synced.slots_changed
+        && !synced.undecodable_changed
+        && !offer
+        && !ice_restart
+    {
         return Ok(());
     }
 
-    for (publisher_peer_id, local_track) in local_tracks {
-        let track_id = local_track.id();
-        if existing_track_ids.contains(track_id) {
-            info!(
-                "The specified track already exists {:?} on {:?}.",
-                track_id, peer_id
+    if synced.slots_added {
+        // The transceivers of the new slots offer only the codecs of the room.
+        senders.codec_policy.apply(&pc).await?;
+    }
+    if synced.slots_added || synced.slots_removed || offer || ice_restart {
+        let options = RTCOfferOptions {
+            ice_restart,
+            ..Default::default()
+        };
+        do_offer(pc.clone(), tx_ws.clone(), Some(options)).await?;
+        senders.resolve_mids(&pc).await;
+    }
+    if synced.slots_added || synced.slots_removed || synced.slots_changed {
+        senders.send_slots(&tx_ws)?;
+    }
+
+    // Pausing is applied after the offer so that every track is announced to the subscriber.
+    apply_forwarding(peer_id, &room, senders, &tx_ws, synced.undecodable_changed).await
+}
+
+/// Assigns the publishers' tracks a subscriber receives to its sender slots.
+///
+/// The track of a new publisher takes over a free slot of the same kind with 'replace_track',
+/// which needs no renegotiation. Only when no slot is free, a new one is added to the pool
+/// unless the pool is full. A slot failing to take over a track is removed from the pool
+/// and the track is given a new one.
+///
+async fn sync_tracks(
+    peer_id: &Uuid,
+    pc: &Arc<RTCPeerConnection>,
+    room: &RoomRef,
+    senders: &mut SubscriberSenders,
+) -> Result<SyncResult, ApplicationError> {
+    info!("Prepare tracks on {:?}.", peer_id);
+
+    if senders.mode == ForwardingMode::Virtual {
+        return Ok(assign_virtual_slots(peer_id, room, senders).await);
+    }
+
+    let subscriber_id = peer_id.clone();
+    let ((local_track_ids, local_tracks), undecodable) = room
+        .ask(move |peer_manager| {
+            let (local_track_ids, local_tracks) =
+                peer_manager.publisher_tracks_info(&subscriber_id);
+            let local_tracks: Vec<_> = local_tracks
+                .into_iter()
+                .map(|(publisher_id, track)| {
+                    let feed = peer_manager.packet_feed(&publisher_id, track.id());
+                    (publisher_id, track, feed)
+                })
+                .collect();
+            (
+                (local_track_ids, local_tracks),
+                peer_manager.undecodable_tracks(&subscriber_id),
+            )
+        })
+        .await
+        .unwrap_or_default();
+
+    let mut result = SyncResult::default();
+    if undecodable != senders.undecodable {
+        for track in undecodable.iter() {
+            warn!(
+                "{:?} can't decode the {:?} of {:?} so leave it out.",
+                peer_id, track.kind, track.peer_id
             );
+        }
+        senders.undecodable = undecodable;
+        result.undecodable_changed = true;
+    }
+    for slot in senders.slots.iter_mut() {
+        let left = match &slot.occupant {
+            Some(occupant) if !local_track_ids.contains(occupant.track.id()) => occupant,
+            _ => continue,
+        };
+        info!("Free the slot of {:?} on {:?}.", left.track.id(), peer_id);
+        slot.sender.replace_track(None).await?;
+        slot.vacate();
+        result.slots_changed = true;
+    }
+
+    let mut video_publishers = vec![];
+    for (publisher_id, local_track, feed) in local_tracks {
+        if senders.holds(local_track.id()) {
             continue;
         }
-        let track_id = track_id.to_owned();
-        match pc
-            .add_track(local_track as Arc<dyn TrackLocal + Send + Sync>)
-            .await
+        let kind = local_track.kind();
+        // The track is shared by the subscribers, and the keyframe replayed to this one alone.
+        let track = ReplayingTrack::attach(&local_track, feed.as_ref());
+
+        if let Some(index) = senders
+            .slots
+            .iter()
+            .position(|slot| slot.kind == kind && slot.occupant.is_none())
         {
-            Ok(rtp_sender) => {
-                let peer_manager_for_rtcp = peer_manager.clone();
-                tokio::spawn(async move {
-                    let mut rtcp_buf = vec![0u8; 1500];
-                    while let Ok((n, _)) = rtp_sender.read(&mut rtcp_buf).await {
-                        let mut buf = &rtcp_buf[..n];
-                        let peer_manager = peer_manager_for_rtcp.lock().unwrap();
-                        // https://stackoverflow.com/questions/33687447/how-to-get-a-reference-to-a-concrete-type-from-a-trait-object
-                        if let Ok(packets) = webrtc::rtcp::packet::unmarshal(&mut buf) {
-                            for packet in packets {
-                                if let Some(pli_packet) =
-                                    packet.as_any().downcast_ref::<PictureLossIndication>()
-                                {
-                                    info!("{:?} on {:?}", pli_packet, peer_id);
-                                    peer_manager.send_to_publisher(
-                                        &publisher_peer_id,
-                                        MessageToPublisher::RTCP(RTCPToPublisher::PLI),
-                                    );
-                                }
-                            }
-                        }
+            info!(
+                "Reuse a slot for the track {:?} on {:?}.",
+                local_track.id(),
+                peer_id
+            );
+            let slot = &mut senders.slots[index];
+            match slot.sender.replace_track(Some(Arc::clone(&track))).await {
+                Ok(()) => {
+                    if kind == RTPCodecType::Video && !has_fresh_keyframe(feed.as_ref()) {
+                        video_publishers.push(publisher_id);
                     }
-                });
+                    slot.occupy(&publisher_id, local_track, feed);
+                    result.slots_changed = true;
+                    continue;
+                }
+                Err(e) => {
+                    // The broken slot is removed, and the track is given a new one instead.
+                    error!("{:?} on {:?} so remove the slot.", e, peer_id);
+                    if let Err(e) = pc.remove_track(&slot.sender).await {
+                        error!("{:?} on {:?}", e, peer_id);
+                    }
+                    senders.slots.remove(index);
+                    result.slots_removed = true;
+                }
+            }
+        }
+
+        let slots_of_kind = senders
+            .slots
+            .iter()
+            .filter(|slot| slot.kind == kind)
+            .count();
+        if slots_of_kind >= senders.max_slots_per_kind {
+            warn!(
+                "No slot is left for the track {:?} on {:?}.",
+                local_track.id(),
+                peer_id
+            );
+            continue;
+        }
+
+        match pc.add_track(track).await {
+            Ok(rtp_sender) => {
+                info!(
+                    "Add a slot for the track {:?} to {:?}.",
+                    local_track.id(),
+                    peer_id
+                );
+                let mut slot = SenderSlot::new(peer_id, kind, rtp_sender, None, room.clone());
+                slot.occupy(&publisher_id, local_track, feed);
+                senders.slots.push(slot);
+                result.slots_added = true;
             }
             Err(e) => error!("{:?} on {:?}", e, peer_id),
         }
+    }
+
+    // The subscriber can't decode a reused video slot until the new publisher sends a keyframe,
+    // unless a cached one is replayed.
+    request_keyframes(room, video_publishers);
 
-        info!("Add a track {:?} to {:?}.", track_id, peer_id);
+    Ok(result)
+}
+
+fn has_fresh_keyframe(feed: Option<&PacketFeed>) -> bool {
+    feed.map(|feed| feed.has_fresh_keyframe()).unwrap_or(false)
+}
+
+/// Asks the publishers for keyframes, without which subscribers can't decode their videos.
+///
+fn request_keyframes(room: &RoomRef, publisher_ids: Vec<Uuid>) {
+    if publisher_ids.is_empty() {
+        return;
+    }
+    room.tell(move |peer_manager| {
+        for publisher_id in publisher_ids {
+            peer_manager.send_to_publisher(
+                &publisher_id,
+                MessageToPublisher::RTCP(RTCPToPublisher::PLI),
+            );
+        }
+    });
+}
+
+/// Lets the forwarded publishers' tracks occupy the virtual tracks of a subscriber.
+///
+/// Publishers that are no longer forwarded leave their virtual tracks to the others,
+/// so switching them needs only a 'Slots' message instead of an offer.
+/// Tracks in other codecs than the virtual tracks' are left out as undecodable.
+///
+async fn assign_virtual_slots(
+    peer_id: &Uuid,
+    room: &RoomRef,
+    senders: &mut SubscriberSenders,
+) -> SyncResult {
+    let subscriber_id = peer_id.clone();
+    let (local_tracks, mut undecodable) = room
+        .ask(move |peer_manager| {
+            let (_, local_tracks) = peer_manager.publisher_tracks_info(&subscriber_id);
+            let local_tracks = local_tracks
+                .into_iter()
+                .filter(|(publisher_id, local_track)| {
+                    peer_manager.is_forwarded(&subscriber_id, publisher_id, local_track.kind())
+                })
+                .collect::<Vec<_>>();
+            (local_tracks, peer_manager.undecodable_tracks(&subscriber_id))
+        })
+        .await
+        .unwrap_or_default();
+    let mut forwarded: Vec<(Uuid, Arc<TrackLocalStaticRTP>)> = vec![];
+    for (publisher_id, local_track) in local_tracks {
+        let kind = local_track.kind();
+        let accepted = virtual_track::codec_of(&senders.codec_policy, kind)
+            .and_then(|codec| CodecName::of(&codec));
+        let codec = CodecName::of(&local_track.codec());
+        if accepted.is_some() && codec == accepted {
+            forwarded.push((publisher_id, local_track));
+            continue;
+        }
+        warn!(
+            "The track {:?} in {} can't occupy a virtual track of {:?} so leave it out.",
+            local_track.id(),
+            local_track.codec().mime_type,
+            peer_id
+        );
+        undecodable.push(PausedTrack {
+            peer_id: publisher_id,
+            kind: MediaKind::of(kind),
+        });
+    }
+    undecodable.sort_by_key(|track| (track.peer_id, track.kind == Some(MediaKind::Video)));
+
+    let mut result = SyncResult::default();
+    if undecodable != senders.undecodable {
+        senders.undecodable = undecodable;
+        result.undecodable_changed = true;
+    }
+
+    let mut changed = false;
+    for slot in senders.slots.iter_mut() {
+        let left = match &slot.occupant {
+            Some(occupant) => !forwarded
+                .iter()
+                .any(|(_, track)| track.id() == occupant.track.id()),
+            None => false,
+        };
+        if left {
+            if let Some(virtual_track) = &slot.virtual_track {
+                virtual_track.switch(peer_id, None);
+            }
+            slot.vacate();
+            changed = true;
+        }
+    }
+
+    let mut occupied = vec![];
+    for (publisher_id, local_track) in forwarded {
+        if senders.holds(local_track.id()) {
+            continue;
+        }
+        let kind = local_track.kind();
+        let index = match senders
+            .slots
+            .iter()
+            .position(|slot| slot.kind == kind && slot.occupant.is_none())
+        {
+            Some(index) => index,
+            None => {
+                warn!(
+                    "No virtual track is left for the track {:?} on {:?}.",
+                    local_track.id(),
+                    peer_id
+                );
+                continue;
+            }
+        };
+        let is_video = kind == RTPCodecType::Video;
+        occupied.push((index, publisher_id, local_track.id().to_owned(), is_video));
+        senders.slots[index].occupy(&publisher_id, local_track, None);
+        changed = true;
+    }
+    result.slots_changed = changed;
+    if occupied.is_empty() {
+        return result;
+    }
+
+    // The subscriber can't decode the new publishers' videos until their next keyframes,
+    // which are asked for unless cached ones are replayed.
+    let publishers: Vec<(Uuid, String, bool)> = occupied
+        .iter()
+        .map(|(_, publisher_id, track_id, is_video)| (*publisher_id, track_id.clone(), *is_video))
+        .collect();
+    let feeds = room
+        .ask(move |peer_manager| {
+            publishers
+                .into_iter()
+                .map(|(publisher_id, track_id, is_video)| {
+                    let feed = peer_manager
+                        .packet_feed(&publisher_id, &track_id)
+                        .map(|feed| feed.subscribe());
+                    let has_keyframe = feed.as_ref().map(|f| f.has_keyframe()).unwrap_or(false);
+                    if is_video && !has_keyframe {
+                        peer_manager.send_to_publisher(
+                            &publisher_id,
+                            MessageToPublisher::RTCP(RTCPToPublisher::PLI),
+                        );
+                    }
+                    feed
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+    for ((index, _, _, _), feed) in occupied.into_iter().zip(feeds) {
+        if let Some(virtual_track) = &senders.slots[index].virtual_track {
+            virtual_track.switch(peer_id, feed);
+        }
+    }
+
+    result
+}
+
+/// Handles 'UpdateForwarding' messages notified when the forwarded tracks may have changed.
+///
+pub async fn handle_update_forwarding_message(
+    peer_id: &Uuid,
+    room: RoomRef,
+    tx_ws: QueueSender<warp::ws::Message>,
+    senders: &mut SubscriberSenders,
+) -> Result<(), ApplicationError> {
+    apply_forwarding(peer_id, &room, senders, &tx_ws, false).await
+}
+
+/// Handles 'Pin' messages with which remote peers choose the videos always forwarded to them.
+///
+pub async fn handle_pin_message(
+    peer_id: &Uuid,
+    pin_message: PinMessage,
+    room: RoomRef,
+    tx_ws: QueueSender<warp::ws::Message>,
+    senders: &mut SubscriberSenders,
+) -> Result<(), ApplicationError> {
+    info!("Pin {:?} on {:?}.", pin_message.peer_ids, peer_id);
+
+    let subscriber_id = peer_id.clone();
+    room.tell(move |peer_manager| {
+        peer_manager.set_pins(&subscriber_id, pin_message.peer_ids.into_iter().collect());
+    });
+
+    apply_forwarding(peer_id, &room, senders, &tx_ws, false).await
+}
+
+/// Handles 'Subscribe' and 'Unsubscribe' messages
+/// with which remote peers choose the tracks they receive.
+///
+/// Only the tracks whose subscription has changed are added or removed.
+///
+pub fn handle_subscription_message(
+    peer_id: &Uuid,
+    subscription_message: SubscriptionMessage,
+    subscribe: bool,
+    room: RoomRef,
+    renegotiation: &mut Renegotiation,
+) {
+    info!(
+        "Subscribe: {} {:?} on {:?}.",
+        subscribe, subscription_message, peer_id
+    );
+
+    let subscriber_id = peer_id.clone();
+    room.tell(move |peer_manager| {
+        peer_manager.update_subscription(&subscriber_id, subscription_message, subscribe);
+    });
+
+    renegotiation.request_sync();
+}
+
+/// Handles 'MediaState' messages with which remote peers tell whether they are muted.
+///
+pub fn handle_media_state_message(peer_id: &Uuid, media_state: MediaState, room: RoomRef) {
+    info!("{:?} on {:?}.", media_state, peer_id);
+
+    let peer_id = peer_id.clone();
+    room.tell(move |peer_manager| {
+        peer_manager.update_media_state(&peer_id, media_state);
+    });
+}
+
+/// Pauses or resumes the tracks of a subscriber according to the room's forwarding policy.
+///
+/// Tracks are paused by detaching them from their senders, so no renegotiation is needed.
+/// The subscriber is told which tracks are paused whenever it changes.
+///
+/// With virtual tracks, the forwarded publishers are assigned to them instead of pausing.
+///
+async fn apply_forwarding(
+    peer_id: &Uuid,
+    room: &RoomRef,
+    senders: &mut SubscriberSenders,
+    tx_ws: &QueueSender<warp::ws::Message>,
+    undecodable_changed: bool,
+) -> Result<(), ApplicationError> {
+    if senders.mode == ForwardingMode::Virtual {
+        let assigned = assign_virtual_slots(peer_id, room, senders).await;
+        if assigned.slots_changed {
+            senders.send_slots(tx_ws)?;
+        }
+        if undecodable_changed || assigned.undecodable_changed {
+            send_to_peer(
+                &ServerMessage::Forwarding(ForwardingMessage {
+                    paused: vec![],
+                    undecodable: senders.undecodable.clone(),
+                }),
+                tx_ws,
+            )?;
+        }
+        return Ok(());
+    }
+
+    let subscriber_id = peer_id.clone();
+    let occupants: Vec<Option<(Uuid, RTPCodecType)>> = senders
+        .slots
+        .iter()
+        .map(|slot| slot.occupant.as_ref().map(|o| (o.publisher_id, slot.kind)))
+        .collect();
+    let decisions: Vec<bool> = match room
+        .ask(move |peer_manager| {
+            occupants
+                .into_iter()
+                .map(|occupant| match occupant {
+                    Some((publisher_id, kind)) => {
+                        peer_manager.is_forwarded(&subscriber_id, &publisher_id, kind)
+                    }
+                    None => true,
+                })
+                .collect()
+        })
+        .await
+    {
+        Some(decisions) => decisions,
+        None => return Ok(()),
+    };
+
+    let mut changed = undecodable_changed;
+    let mut resumed_video_publishers = vec![];
+    for (slot, forwarded) in senders.slots.iter_mut().zip(decisions) {
+        let occupant = match slot.occupant.as_mut() {
+            Some(o) => o,
+            None => continue,
+        };
+        if forwarded != occupant.paused {
+            continue;
+        }
+
+        if forwarded {
+            info!(
+                "Resume the track {:?} on {:?}.",
+                occupant.track.id(),
+                peer_id
+            );
+            slot.sender
+                .replace_track(Some(ReplayingTrack::attach(
+                    &occupant.track,
+                    occupant.feed.as_ref(),
+                )))
+                .await?;
+            if slot.kind == RTPCodecType::Video && !has_fresh_keyframe(occupant.feed.as_ref()) {
+                resumed_video_publishers.push(occupant.publisher_id.clone());
+            }
+        } else {
+            info!(
+                "Pause the track {:?} on {:?}.",
+                occupant.track.id(),
+                peer_id
+            );
+            slot.sender.replace_track(None).await?;
+        }
+        occupant.paused = !forwarded;
+        changed = true;
+    }
+
+    request_keyframes(room, resumed_video_publishers);
+
+    if changed {
+        let paused = senders
+            .slots
+            .iter()
+            .filter_map(|slot| slot.occupant.as_ref().map(|o| (slot.kind, o)))
+            .filter(|(_, o)| o.paused)
+            .map(|(kind, o)| PausedTrack {
+                peer_id: o.publisher_id.clone(),
+                kind: MediaKind::of(kind),
+            })
+            .collect();
+
+        send_to_peer(
+            &ServerMessage::Forwarding(ForwardingMessage {
+                paused,
+                undecodable: senders.undecodable.clone(),
+            }),
+            tx_ws,
+        )?;
     }
-    do_offer(pc, tx_ws).await?;
 
     Ok(())
 }
 
 /// Responds to Ping message.
-/// 
-pub fn handle_ping(tx_ws: UnboundedSender<warp::ws::Message>) -> Result<(), ApplicationError> {
+///
+pub fn handle_ping(tx_ws: QueueSender<warp::ws::Message>) -> Result<(), ApplicationError> {
+    send_to_peer(&ServerMessage::Pong, &tx_ws)
+}
+
+/// Sends a message to the remote peer through its WebSocket.
+///
+pub fn send_to_peer(
+    msg: &ServerMessage,
+    tx_ws: &QueueSender<warp::ws::Message>,
+) -> Result<(), ApplicationError> {
+    tx_ws.send(msg.to_ws_message()?)?;
+
+    Ok(())
+}
+
+/// Handles 'RestartIce' messages by requesting an offer that restarts ICE,
+/// with which the existing RTCPeerConnection moves to a new network path.
+///
+/// An offer left unanswered, e.g. because the WebSocket was lost, is rolled back
+/// so that the restart is not postponed forever.
+///
+pub async fn handle_restart_ice_message(
+    peer_id: &Uuid,
+    peer_connection: Arc<RTCPeerConnection>,
+    renegotiation: &mut Renegotiation,
+) -> Result<(), ApplicationError> {
+    info!("Restart ICE on {:?}.", peer_id);
 
-    tx_ws.send(Message::text(format!("{{
-        \"msg_type\": \"{:?}\"
-    }}", SubscriberMessageType::Pong)))?;
+    if peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
+        info!("Roll back the unanswered offer on {:?}.", peer_id);
+        rollback(&peer_connection).await?;
+    }
+    renegotiation.request_offer(true);
+
+    Ok(())
+}
+
+async fn rollback(peer_connection: &RTCPeerConnection) -> Result<(), ApplicationError> {
+    if let Some(mut rollback) = peer_connection.local_description().await {
+        rollback.sdp_type = RTCSdpType::Rollback;
+        peer_connection.set_local_description(rollback).await?;
+    }
 
     Ok(())
 }
 
 /// Creates offer.
 ///
-pub async fn do_offer(
+async fn do_offer(
     peer_connection: Arc<RTCPeerConnection>,
-    tx_ws: UnboundedSender<warp::ws::Message>,
+    tx_ws: QueueSender<warp::ws::Message>,
+    options: Option<RTCOfferOptions>,
 ) -> Result<(), ApplicationError> {
-    let offer = peer_connection.create_offer(None).await?;
+    let offer = peer_connection.create_offer(options).await?;
 
     // TODO For some reason, this promise resolves before ice_gathering_state become complete.
     // let mut gather_complete = peer_connection.gathering_complete_promise().await;
@@ -595,15 +2338,124 @@ pub async fn do_offer(
     // let _ = gather_complete.recv().await;
 
     if let Some(local_description) = peer_connection.local_description().await {
-        let sdp_str = serde_json::to_string(&local_description)?;
-
-        let ret_message = serde_json::to_string(&SubscriberMessage {
-            msg_type: SubscriberMessageType::Offer,
-            message: sdp_str,
-        })?;
-
-        tx_ws.send(Message::text(ret_message))?
+        let offer = SessionDescription::from_rtc(&local_description)?;
+        send_to_peer(&ServerMessage::Offer(offer), &tx_ws)?;
     }
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{self, Overflow};
+    use tokio::sync::mpsc::unbounded_channel;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+    fn add_peer(peer_manager: &mut PeerManager, peer_id: &Uuid) {
+        let (tx_pub, _) = queue::channel("publisher", peer_id, 1, Overflow::DropNewest);
+        let (tx_sub, _) = queue::channel("signaling", peer_id, 1, Overflow::DropNewest);
+        let (tx_data, _) = queue::channel("data", peer_id, 1, Overflow::DropNewest);
+        let (tx_teardown, _) = unbounded_channel();
+        peer_manager.add_peer(
+            peer_id,
+            RoomMember {
+                member_id: 1,
+                room_id: 1,
+                room_name: "room".to_owned(),
+                member_name: "member".to_owned(),
+                last_n: Some(1),
+                duplicate_session_policy: None,
+                video_codecs: None,
+                audio_codecs: None,
+            },
+            tx_pub,
+            tx_sub,
+            tx_data,
+            tx_teardown,
+        );
+    }
+
+    fn new_track(mime_type: &str) -> Arc<TrackLocalStaticRTP> {
+        Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: mime_type.to_owned(),
+                ..Default::default()
+            },
+            format!("{}{:?}", TRACK_NAME_PREF, Uuid::new_v4()),
+            "stream".to_owned(),
+        ))
+    }
+
+    #[test]
+    fn remove_peer_leaves_no_state_of_the_peer() {
+        // Proximity is enabled so that the avatar's move is handled in full.
+        let config = RoomConfig {
+            default_last_n: 0,
+            proximity_distance: 100.0,
+            default_duplicate_session_policy: DuplicateSessionPolicy::Allow,
+            receiver_report_interval: Duration::from_millis(1000),
+        };
+        let mut peer_manager = PeerManager::new(1, config);
+        let peer_id = Uuid::new_v4();
+        let another_id = Uuid::new_v4();
+        add_peer(&mut peer_manager, &peer_id);
+        add_peer(&mut peer_manager, &another_id);
+
+        peer_manager.add_track(&peer_id, new_track("video/VP8"));
+        peer_manager.add_track(&peer_id, new_track("audio/opus"));
+        peer_manager.update_audio_level(&peer_id, 0);
+        peer_manager.observe_avatar_message(
+            &peer_id,
+            r#"{"msgType":4,"id":"sfu-stream-1","direction":0,"coord":{"x":10,"y":20}}"#,
+        );
+        peer_manager.update_media_state(
+            &peer_id,
+            MediaState {
+                audio_muted: true,
+                video_muted: false,
+            },
+        );
+        peer_manager.set_pins(&peer_id, [another_id].into_iter().collect());
+        peer_manager.set_pins(&another_id, [peer_id].into_iter().collect());
+        peer_manager.update_subscription(
+            &another_id,
+            SubscriptionMessage {
+                peer_ids: Some(vec![peer_id]),
+                kinds: Some(vec![MediaKind::Video]),
+            },
+            false,
+        );
+        assert!(peer_manager.holds_state_of(&peer_id));
+
+        assert!(peer_manager.remove_peer(&peer_id));
+        assert!(!peer_manager.holds_state_of(&peer_id));
+        assert!(!peer_manager.remove_peer(&peer_id));
+
+        assert!(peer_manager.remove_peer(&another_id));
+        assert!(!peer_manager.holds_state_of(&another_id));
+        assert!(peer_manager.speakers.dominant().is_none());
+        assert!(peer_manager.speaker_order.is_empty());
+    }
+
+    #[test]
+    fn renegotiation_waits_for_answer_to_offer_in_flight() {
+        let mut renegotiation = Renegotiation::new(Duration::from_millis(100));
+        renegotiation.request_offer(false);
+        assert!(renegotiation.due_at().is_some());
+
+        assert_eq!(renegotiation.take(RTCSignalingState::HaveLocalOffer), None);
+        assert_eq!(renegotiation.due_at(), None);
+
+        renegotiation.on_answered();
+        assert!(renegotiation.due_at().is_some());
+        assert_eq!(
+            renegotiation.take(RTCSignalingState::Stable),
+            Some((false, true, false))
+        );
+        assert_eq!(renegotiation.due_at(), None);
+
+        // Nothing is made on an answer when nothing is requested.
+        renegotiation.on_answered();
+        assert_eq!(renegotiation.due_at(), None);
+    }
+}
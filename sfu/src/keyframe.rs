@@ -0,0 +1,412 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use webrtc::rtp::packet::Packet;
+
+use log::debug;
+
+use crate::codec::CodecName;
+
+/// Keyframes split into more packets than this aren't cached.
+const MAX_KEYFRAME_PACKETS: usize = 1024;
+
+/// Whether the keyframes of the codec can be told from its payloads.
+///
+fn supports(codec: CodecName) -> bool {
+    matches!(codec, CodecName::Vp8 | CodecName::Vp9 | CodecName::H264(_))
+}
+
+/// Whether the payload starts a keyframe of the codec.
+///
+pub fn starts_keyframe(codec: CodecName, payload: &[u8]) -> bool {
+    match codec {
+        CodecName::Vp8 => vp8_starts_keyframe(payload),
+        CodecName::Vp9 => vp9_starts_keyframe(payload),
+        CodecName::H264(_) => h264_starts_keyframe(payload),
+        _ => false,
+    }
+}
+
+/// Reads the VP8 payload descriptor and the first byte of the payload header (RFC 7741).
+///
+/// The first partition of a frame starts with the S bit set and the partition index 0,
+/// and its payload header has the P bit cleared on keyframes.
+///
+fn vp8_starts_keyframe(payload: &[u8]) -> bool {
+    let first = match payload.first() {
+        Some(first) => *first,
+        None => return false,
+    };
+    let extended = first & 0x80 != 0;
+    let start_of_partition = first & 0x10 != 0;
+    let partition_index = first & 0x07;
+    if !start_of_partition || partition_index != 0 {
+        return false;
+    }
+
+    let mut offset = 1;
+    if extended {
+        let extension = match payload.get(offset) {
+            Some(extension) => *extension,
+            None => return false,
+        };
+        offset += 1;
+        // The PictureID is 15 bits long if its M bit is set, otherwise 7 bits.
+        if extension & 0x80 != 0 {
+            match payload.get(offset) {
+                Some(picture_id) if picture_id & 0x80 != 0 => offset += 2,
+                Some(_) => offset += 1,
+                None => return false,
+            }
+        }
+        // TL0PICIDX
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        // TID and KEYIDX share a byte.
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+
+    payload
+        .get(offset)
+        .map(|header| header & 0x01 == 0)
+        .unwrap_or(false)
+}
+
+/// Reads the VP9 payload descriptor (draft-ietf-payload-vp9).
+///
+/// A keyframe starts with the B bit set and the P bit cleared on the base spatial layer.
+///
+fn vp9_starts_keyframe(payload: &[u8]) -> bool {
+    let first = match payload.first() {
+        Some(first) => *first,
+        None => return false,
+    };
+    let has_picture_id = first & 0x80 != 0;
+    let inter_predicted = first & 0x40 != 0;
+    let has_layer_indices = first & 0x20 != 0;
+    let start_of_frame = first & 0x08 != 0;
+    if inter_predicted || !start_of_frame {
+        return false;
+    }
+    if !has_layer_indices {
+        return true;
+    }
+
+    let mut offset = 1;
+    if has_picture_id {
+        match payload.get(offset) {
+            Some(picture_id) if picture_id & 0x80 != 0 => offset += 2,
+            Some(_) => offset += 1,
+            None => return false,
+        }
+    }
+    payload
+        .get(offset)
+        .map(|layer_indices| (layer_indices >> 1) & 0x07 == 0)
+        .unwrap_or(false)
+}
+
+/// Reads the NAL unit headers of the H.264 payload (RFC 6184).
+///
+/// A keyframe starts with the SPS or, when the encoder doesn't repeat it, the IDR slice.
+/// They may be aggregated in a STAP-A packet, and the IDR slice may start a FU-A.
+///
+fn h264_starts_keyframe(payload: &[u8]) -> bool {
+    const NAL_IDR: u8 = 5;
+    const NAL_SPS: u8 = 7;
+    const NAL_STAP_A: u8 = 24;
+    const NAL_FU_A: u8 = 28;
+
+    let nal_type = match payload.first() {
+        Some(header) => header & 0x1f,
+        None => return false,
+    };
+    match nal_type {
+        NAL_IDR | NAL_SPS => true,
+        NAL_STAP_A => {
+            let mut offset = 1;
+            while let Some(size) = payload.get(offset..offset + 2) {
+                let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+                match payload.get(offset + 2) {
+                    Some(header) if matches!(header & 0x1f, NAL_IDR | NAL_SPS) => return true,
+                    Some(_) => offset += 2 + size,
+                    None => return false,
+                }
+            }
+            false
+        }
+        NAL_FU_A => payload
+            .get(1)
+            .map(|fu_header| fu_header & 0x80 != 0 && fu_header & 0x1f == NAL_IDR)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// The packets of the most recent keyframe of a publisher's video track,
+/// replayed to subscribers that start receiving the track so that they render it at once.
+///
+pub struct KeyframeCache {
+    codec: CodecName,
+    max_age: Duration,
+    /// The packets of the keyframe being received, which all share its timestamp.
+    receiving: Vec<Packet>,
+    /// The latest complete keyframe and when it was completed.
+    latest: Option<(Arc<Vec<Packet>>, Instant)>,
+}
+
+impl KeyframeCache {
+    /// Creates the cache of a track, or None if its codec isn't supported.
+    ///
+    pub fn new(codec: CodecName, max_age: Duration) -> Option<Self> {
+        if !supports(codec) {
+            return None;
+        }
+        Some(KeyframeCache {
+            codec,
+            max_age,
+            receiving: vec![],
+            latest: None,
+        })
+    }
+
+    pub fn push(&mut self, packet: &Packet) {
+        let timestamp = packet.header.timestamp;
+        let receiving_timestamp = self.receiving.first().map(|p| p.header.timestamp);
+        if starts_keyframe(self.codec, &packet.payload) {
+            if receiving_timestamp != Some(timestamp) {
+                self.receiving.clear();
+            }
+        } else if receiving_timestamp != Some(timestamp) {
+            // The keyframe ends with the marker bit, so another frame means some of it is lost.
+            self.receiving.clear();
+            return;
+        }
+
+        if self.receiving.len() >= MAX_KEYFRAME_PACKETS {
+            debug!(
+                "A keyframe of more than {} packets isn't cached.",
+                MAX_KEYFRAME_PACKETS
+            );
+            self.receiving.clear();
+            return;
+        }
+        self.receiving.push(packet.clone());
+
+        if packet.header.marker {
+            self.complete();
+        }
+    }
+
+    /// The packets of the latest keyframe in order, unless it is older than the maximum age.
+    ///
+    pub fn fresh(&self) -> Option<Arc<Vec<Packet>>> {
+        self.latest
+            .as_ref()
+            .filter(|(_, completed_at)| completed_at.elapsed() < self.max_age)
+            .map(|(packets, _)| Arc::clone(packets))
+    }
+
+    fn complete(&mut self) {
+        let mut packets = std::mem::take(&mut self.receiving);
+        // Sorted relative to the first packet received, since the sequence numbers may wrap.
+        let first = packets[0].header.sequence_number;
+        packets.sort_by_key(|p| p.header.sequence_number.wrapping_sub(first) as i16);
+        packets.dedup_by_key(|p| p.header.sequence_number);
+
+        // A keyframe with a gap can't be decoded.
+        let span = packets[packets.len() - 1]
+            .header
+            .sequence_number
+            .wrapping_sub(packets[0].header.sequence_number) as usize;
+        if span + 1 != packets.len() {
+            return;
+        }
+        self.latest = Some((Arc::new(packets), Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+
+    const MAX_AGE: Duration = Duration::from_millis(3000);
+
+    /// The VP8 payloads starting a keyframe, starting an interframe and continuing a frame.
+    const VP8_KEY: &[u8] = &[0x10, 0x00];
+    const VP8_DELTA: &[u8] = &[0x10, 0x01];
+    const VP8_CONTINUED: &[u8] = &[0x00, 0xff];
+
+    fn packet(sequence_number: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                marker,
+                ..Default::default()
+            },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    fn cached_sequence_numbers(cache: &KeyframeCache) -> Option<Vec<u16>> {
+        cache
+            .fresh()
+            .map(|packets| packets.iter().map(|p| p.header.sequence_number).collect())
+    }
+
+    #[test]
+    fn vp8_keyframes() {
+        assert!(vp8_starts_keyframe(VP8_KEY));
+        assert!(!vp8_starts_keyframe(VP8_DELTA));
+        assert!(!vp8_starts_keyframe(VP8_CONTINUED));
+        // A partition other than the first.
+        assert!(!vp8_starts_keyframe(&[0x11, 0x00]));
+        // The extended descriptor with a 15 bit PictureID, TL0PICIDX and KEYIDX.
+        assert!(vp8_starts_keyframe(&[
+            0x90, 0xd0, 0x80, 0x01, 0x02, 0x03, 0x00
+        ]));
+        assert!(!vp8_starts_keyframe(&[
+            0x90, 0xd0, 0x80, 0x01, 0x02, 0x03, 0x01
+        ]));
+        // The extended descriptor with a 7 bit PictureID.
+        assert!(vp8_starts_keyframe(&[0x90, 0x80, 0x01, 0x00]));
+        assert!(!vp8_starts_keyframe(&[]));
+        assert!(!vp8_starts_keyframe(&[0x90]));
+        assert!(!vp8_starts_keyframe(&[0x90, 0x80, 0x81, 0x01]));
+    }
+
+    #[test]
+    fn vp9_keyframes() {
+        assert!(vp9_starts_keyframe(&[0x08]));
+        assert!(!vp9_starts_keyframe(&[0x48]));
+        // Not the start of a frame.
+        assert!(!vp9_starts_keyframe(&[0x00]));
+        // A 15 bit PictureID followed by the layer indices of the base spatial layer.
+        assert!(vp9_starts_keyframe(&[0xa8, 0x81, 0x23, 0x00]));
+        // The second spatial layer.
+        assert!(!vp9_starts_keyframe(&[0xa8, 0x81, 0x23, 0x02]));
+        // A 7 bit PictureID.
+        assert!(vp9_starts_keyframe(&[0xa8, 0x01, 0x00]));
+        assert!(!vp9_starts_keyframe(&[]));
+        assert!(!vp9_starts_keyframe(&[0xa8, 0x81]));
+    }
+
+    #[test]
+    fn h264_keyframes() {
+        // IDR, SPS and non-IDR slices.
+        assert!(h264_starts_keyframe(&[0x65, 0x88]));
+        assert!(h264_starts_keyframe(&[0x67, 0x42]));
+        assert!(!h264_starts_keyframe(&[0x41, 0x9a]));
+        // A STAP-A of an access unit delimiter and an SPS.
+        assert!(h264_starts_keyframe(&[
+            0x78, 0x00, 0x02, 0x09, 0xf0, 0x00, 0x02, 0x67, 0x42
+        ]));
+        // A STAP-A of a non-IDR slice.
+        assert!(!h264_starts_keyframe(&[0x78, 0x00, 0x02, 0x41, 0x9a]));
+        // The start of a FU-A of an IDR slice, its continuation and a FU-A of a non-IDR slice.
+        assert!(h264_starts_keyframe(&[0x7c, 0x85, 0x88]));
+        assert!(!h264_starts_keyframe(&[0x7c, 0x05, 0x88]));
+        assert!(!h264_starts_keyframe(&[0x7c, 0x81, 0x9a]));
+        assert!(!h264_starts_keyframe(&[]));
+        assert!(!h264_starts_keyframe(&[0x78, 0x00]));
+        assert!(!h264_starts_keyframe(&[0x7c]));
+    }
+
+    #[test]
+    fn starts_keyframe_of_codec() {
+        assert!(starts_keyframe(CodecName::Vp8, VP8_KEY));
+        assert!(starts_keyframe(CodecName::Vp9, &[0x08]));
+        assert!(!starts_keyframe(CodecName::Opus, VP8_KEY));
+        assert!(!starts_keyframe(CodecName::Av1, VP8_KEY));
+    }
+
+    #[test]
+    fn unsupported_codecs_have_no_cache() {
+        assert!(KeyframeCache::new(CodecName::Opus, MAX_AGE).is_none());
+        assert!(KeyframeCache::new(CodecName::Av1, MAX_AGE).is_none());
+        assert!(KeyframeCache::new(CodecName::Vp8, MAX_AGE).is_some());
+    }
+
+    #[test]
+    fn caches_complete_keyframe() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, MAX_AGE).unwrap();
+        cache.push(&packet(10, 1000, false, VP8_KEY));
+        cache.push(&packet(11, 1000, false, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), None);
+
+        cache.push(&packet(12, 1000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), Some(vec![10, 11, 12]));
+
+        // Interframes leave the keyframe cached.
+        cache.push(&packet(13, 2000, true, VP8_DELTA));
+        assert_eq!(cached_sequence_numbers(&cache), Some(vec![10, 11, 12]));
+
+        cache.push(&packet(14, 3000, false, VP8_KEY));
+        cache.push(&packet(15, 3000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), Some(vec![14, 15]));
+    }
+
+    #[test]
+    fn rejects_keyframe_with_gap() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, MAX_AGE).unwrap();
+        cache.push(&packet(10, 1000, false, VP8_KEY));
+        cache.push(&packet(12, 1000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), None);
+    }
+
+    #[test]
+    fn sorts_packets_in_sequence() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, MAX_AGE).unwrap();
+        cache.push(&packet(10, 1000, false, VP8_KEY));
+        cache.push(&packet(12, 1000, false, VP8_CONTINUED));
+        cache.push(&packet(11, 1000, false, VP8_CONTINUED));
+        cache.push(&packet(11, 1000, false, VP8_CONTINUED));
+        cache.push(&packet(13, 1000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), Some(vec![10, 11, 12, 13]));
+    }
+
+    #[test]
+    fn sorts_wrapped_sequence_numbers() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, MAX_AGE).unwrap();
+        cache.push(&packet(65534, 1000, false, VP8_KEY));
+        cache.push(&packet(0, 1000, false, VP8_CONTINUED));
+        cache.push(&packet(65535, 1000, false, VP8_CONTINUED));
+        cache.push(&packet(1, 1000, true, VP8_CONTINUED));
+        assert_eq!(
+            cached_sequence_numbers(&cache),
+            Some(vec![65534, 65535, 0, 1])
+        );
+    }
+
+    #[test]
+    fn another_frame_discards_keyframe_being_received() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, MAX_AGE).unwrap();
+        cache.push(&packet(10, 1000, false, VP8_KEY));
+        cache.push(&packet(11, 2000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), None);
+
+        // A packet continuing no keyframe is ignored.
+        cache.push(&packet(12, 3000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), None);
+
+        // A new keyframe replaces the one being received.
+        cache.push(&packet(20, 4000, false, VP8_KEY));
+        cache.push(&packet(30, 5000, false, VP8_KEY));
+        cache.push(&packet(31, 5000, true, VP8_CONTINUED));
+        assert_eq!(cached_sequence_numbers(&cache), Some(vec![30, 31]));
+    }
+
+    #[test]
+    fn stale_keyframe_isnt_replayed() {
+        let mut cache = KeyframeCache::new(CodecName::Vp8, Duration::ZERO).unwrap();
+        cache.push(&packet(10, 1000, true, VP8_KEY));
+        assert_eq!(cached_sequence_numbers(&cache), None);
+    }
+}
@@ -1,21 +1,17 @@
 use std::convert::From;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
 
+use futures::stream::SplitSink;
 use futures::{SinkExt, StreamExt};
 use tokio::sync::mpsc::unbounded_channel;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::cors::CorsForbidden;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::MediaEngine;
-use webrtc::api::APIBuilder;
-use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-// use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
 use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
@@ -24,23 +20,46 @@ use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_remote::TrackRemote;
 
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
 use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::receiver_report::ReceiverReport;
 
 use dotenv::dotenv;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
+mod access;
+mod avatar;
+mod codec;
+mod config;
 mod data;
 mod errors;
+mod feedback;
 mod handler;
 mod ice;
+mod keyframe;
 mod logger;
-
+mod protocol;
+mod queue;
+mod room;
+mod rtc;
+mod session;
+mod speaker;
+mod virtual_track;
+
+use crate::access::{AccessPolicy, AccessPolicyRef, OriginNotAllowed, TooManyRequests};
+use crate::codec::CodecPolicy;
 use crate::data::{DBPool, MemberToken, RoomMember, RoomMemberDao};
-use crate::errors::ApplicationError;
+use crate::errors::{ApplicationError, ErrorBody, ErrorCode};
+use crate::feedback::{KeyframeRequest, KeyframeRequestMetrics, KeyframeRequestThrottle};
 use crate::handler::{
-    MessageToPublisher, PeerManager, PeerManagerRef, RTCPToPublisher, SubscriberMessage,
-    SubscriberMessageType, ToSubscriberDataChannelMessage,
+    MessageToPublisher, NegotiationRole, RTCPToPublisher, Renegotiation, SubscriberMessage,
+    SubscriberSenders, TeardownReason, ToSubscriberDataChannelMessage,
 };
+use crate::protocol::{ClientMessage, ConnectQuery, HelloMessage, ServerMessage};
+use crate::queue::{Overflow, QueueConfig, QueueMetrics};
+use crate::room::{RoomRegistry, RoomRegistryRef};
+use crate::rtc::{IceNetworkConfig, RtcApi, RtcApiRef};
+use crate::session::{ActivityMonitor, Credentials, LivenessConfig, ResumeHandle};
 
 const SECRET_HEADER_KEY: &str = "X-W-Chat-Secret";
 
@@ -50,11 +69,6 @@ struct OfferBody {
     _type: String,
 }
 
-#[derive(Serialize)]
-struct MessageResponse {
-    message: String,
-}
-
 #[derive(Serialize)]
 struct NameResponse {
     name: String,
@@ -62,6 +76,15 @@ struct NameResponse {
 
 #[tokio::main]
 async fn main() {
+    // Prints the JSON Schema of the signaling messages for generating the client's types.
+    if std::env::args().any(|arg| arg == "--signaling-schema") {
+        match protocol::json_schema() {
+            Ok(schema) => println!("{}", schema),
+            Err(e) => eprintln!("{:?}", e),
+        }
+        return;
+    }
+
     dotenv().ok();
     logger::init_logger();
     let port: u16 = std::env::var("PORT")
@@ -73,7 +96,10 @@ async fn main() {
 
     let context = warp::path("app");
     let ws_context = warp::path("ws-app");
-    let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+    let rooms = Arc::new(RoomRegistry::new());
+    let rtc_api =
+        Arc::new(RtcApi::new(&IceNetworkConfig::from_env(), CodecPolicy::from_env()).expect("Invalid WebRTC settings."));
+    let access_policy = Arc::new(AccessPolicy::from_env());
 
     let ice_servers = context
         .and(warp::path("ice-servers"))
@@ -86,29 +112,150 @@ async fn main() {
         .and(warp::header(SECRET_HEADER_KEY))
         .and(warp::path::param())
         .and(with_db(db_pool.clone()))
-        .and(with_peer_manager(peer_manager.clone()))
+        .and(with_rooms(rooms.clone()))
         .and_then(member_name);
 
     let subscribe = ws_context
         .and(warp::path("subscribe"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::query::<ConnectQuery>())
+        .and(warp::ws())
+        .and(access::check_origin(access_policy.clone()))
+        .and(access::limit_connections_per_ip(access_policy.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_rtc_api(rtc_api.clone()))
+        .and(with_db(db_pool.clone()))
+        .and(with_access_policy(access_policy.clone()))
+        .map(
+            |subprotocols: Option<String>,
+             query: ConnectQuery,
+             ws: warp::ws::Ws,
+             rooms: RoomRegistryRef,
+             rtc_api: RtcApiRef,
+             room_member_dao: RoomMemberDao,
+             access_policy: AccessPolicyRef| {
+                let credentials = Credentials::from_subprotocols(subprotocols.as_deref());
+                let reply = ws.on_upgrade(|websocket| {
+                    handle_peer(
+                        credentials,
+                        query,
+                        websocket,
+                        rooms,
+                        rtc_api,
+                        room_member_dao,
+                        access_policy,
+                    )
+                });
+                with_subprotocol(reply, subprotocols)
+            },
+        );
+
+    // Deprecated since the token in the path ends up in access logs and browser history.
+    let subscribe_with_token_in_path = ws_context
+        .and(warp::path("subscribe"))
+        .and(warp::path::param())
+        .and(warp::query::<ConnectQuery>())
+        .and(warp::ws())
+        .and(access::check_origin(access_policy.clone()))
+        .and(access::limit_connections_per_ip(access_policy.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_rtc_api(rtc_api.clone()))
+        .and(with_db(db_pool.clone()))
+        .and(with_access_policy(access_policy.clone()))
+        .map(
+            |token: String,
+             query: ConnectQuery,
+             ws: warp::ws::Ws,
+             rooms: RoomRegistryRef,
+             rtc_api: RtcApiRef,
+             room_member_dao: RoomMemberDao,
+             access_policy: AccessPolicyRef| {
+                let credentials = Credentials::from_path(token, None);
+                ws.on_upgrade(|websocket| {
+                    handle_peer(
+                        credentials,
+                        query,
+                        websocket,
+                        rooms,
+                        rtc_api,
+                        room_member_dao,
+                        access_policy,
+                    )
+                })
+            },
+        );
+
+    let resume = ws_context
+        .and(warp::path("resume"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and(access::check_origin(access_policy.clone()))
+        .and(access::limit_connections_per_ip(access_policy.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_db(db_pool.clone()))
+        .and(with_access_policy(access_policy.clone()))
+        .map(
+            |subprotocols: Option<String>,
+             ws: warp::ws::Ws,
+             rooms: RoomRegistryRef,
+             room_member_dao: RoomMemberDao,
+             access_policy: AccessPolicyRef| {
+                let credentials = Credentials::from_subprotocols(subprotocols.as_deref());
+                let reply = ws.on_upgrade(|websocket| {
+                    resume_peer(
+                        credentials,
+                        websocket,
+                        rooms,
+                        room_member_dao,
+                        access_policy,
+                    )
+                });
+                with_subprotocol(reply, subprotocols)
+            },
+        );
+
+    // Deprecated for the same reason as 'subscribe_with_token_in_path'.
+    let resume_with_token_in_path = ws_context
+        .and(warp::path("resume"))
+        .and(warp::path::param())
         .and(warp::path::param())
         .and(warp::ws())
-        .and(with_peer_manager(peer_manager.clone()))
+        .and(access::check_origin(access_policy.clone()))
+        .and(access::limit_connections_per_ip(access_policy.clone()))
+        .and(with_rooms(rooms.clone()))
         .and(with_db(db_pool.clone()))
+        .and(with_access_policy(access_policy.clone()))
         .map(
             |token: String,
+             resume_token: String,
              ws: warp::ws::Ws,
-             peer_manager: PeerManagerRef,
-             room_member_dao: RoomMemberDao| {
+             rooms: RoomRegistryRef,
+             room_member_dao: RoomMemberDao,
+             access_policy: AccessPolicyRef| {
+                let credentials = Credentials::from_path(token, Some(resume_token));
                 ws.on_upgrade(|websocket| {
-                    handle_peer(token, websocket, peer_manager, room_member_dao)
+                    resume_peer(
+                        credentials,
+                        websocket,
+                        rooms,
+                        room_member_dao,
+                        access_policy,
+                    )
                 })
             },
         );
 
-    let route = ice_servers
+    let rest = ice_servers
         .or(member_name)
+        .with(access_policy.cors(&[SECRET_HEADER_KEY]));
+
+    let route = rest
         .or(subscribe)
+        .or(subscribe_with_token_in_path)
+        .or(resume)
+        .or(resume_with_token_in_path)
         .recover(handle_rejection);
 
     warp::serve(route).run(([0, 0, 0, 0], port)).await;
@@ -120,10 +267,36 @@ fn with_db(
     warp::any().map(move || RoomMemberDao::new(db_pool.clone()))
 }
 
-fn with_peer_manager(
-    peer_manager: PeerManagerRef,
-) -> impl Filter<Extract = (PeerManagerRef,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || peer_manager.clone())
+fn with_access_policy(
+    access_policy: AccessPolicyRef,
+) -> impl Filter<Extract = (AccessPolicyRef,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || access_policy.clone())
+}
+
+fn with_rooms(
+    rooms: RoomRegistryRef,
+) -> impl Filter<Extract = (RoomRegistryRef,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || rooms.clone())
+}
+
+fn with_rtc_api(
+    rtc_api: RtcApiRef,
+) -> impl Filter<Extract = (RtcApiRef,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || rtc_api.clone())
+}
+
+/// Selects the signaling subprotocol if the peer has offered any subprotocols,
+/// since browsers fail the connection when none of them is selected.
+///
+fn with_subprotocol(reply: impl Reply + 'static, subprotocols: Option<String>) -> Box<dyn Reply> {
+    match subprotocols {
+        Some(_) => Box::new(warp::reply::with_header(
+            reply,
+            "sec-websocket-protocol",
+            session::SIGNALING_SUBPROTOCOL,
+        )),
+        None => Box::new(reply),
+    }
 }
 
 /// The endpoint serving the dictionaries of ice servers.
@@ -146,29 +319,33 @@ async fn member_name(
     token: String,
     peer_id: String,
     room_member_dao: RoomMemberDao,
-    peer_manager: PeerManagerRef,
+    rooms: RoomRegistryRef,
 ) -> Result<impl Reply, Rejection> {
-    if let Err(e) = check_member_token(token, room_member_dao).await {
-        return Ok(e);
-    }
+    let room_member = match check_member_token(token, room_member_dao).await {
+        Ok(room_member) => room_member,
+        Err(e) => return Ok(e),
+    };
 
     let peer_id = match Uuid::parse_str(&peer_id) {
         Ok(peer_id) => peer_id,
         Err(e) => {
             error!("{:?}", e);
-            return Ok(resp_with_message(
-                "Invalid id format".to_owned(),
-                StatusCode::BAD_REQUEST,
-            ));
+            return Ok(error_reply(&ErrorBody::new(
+                ErrorCode::InvalidRequest,
+                "Invalid id format.",
+            )));
         }
     };
 
-    let peer_manager = peer_manager.lock().unwrap();
-    let name = if let Some(name) = peer_manager.get_name_by_peer_id(&peer_id) {
-        name
-    } else {
-        "-".to_owned()
+    // Only the names of the peers in the same room are told.
+    let name = match rooms.get(room_member.room_id) {
+        Some(room) => room
+            .ask(move |peer_manager| peer_manager.get_name_by_peer_id(&peer_id))
+            .await
+            .flatten(),
+        None => None,
     };
+    let name = name.unwrap_or("-".to_owned());
 
     Ok(ok_with_json(&NameResponse { name }))
 }
@@ -177,23 +354,11 @@ async fn check_member_token(
     token: String,
     room_member_dao: RoomMemberDao,
 ) -> Result<RoomMember, warp::reply::WithStatus<warp::reply::Json>> {
-    let member_token = match MemberToken::decode(&token) {
-        Ok(mt) => mt,
-        Err(e) => {
-            error!("{:?}", e);
-            return Err(resp_with_message(
-                "Invalid token".to_owned(),
-                StatusCode::UNAUTHORIZED,
-            ));
-        }
-    };
-
-    room_member_dao
-        .find_room_member(&member_token.member_id, &member_token.secret_token)
+    fetch_room_member(token, room_member_dao)
         .await
         .map_err(|e| {
             error!("{:?}", e);
-            resp_with_message("Invalid token".to_owned(), StatusCode::UNAUTHORIZED)
+            error_reply(&ErrorBody::from(&e))
         })
 }
 
@@ -201,75 +366,303 @@ async fn fetch_room_member(
     token: String,
     room_member_dao: RoomMemberDao,
 ) -> Result<RoomMember, ApplicationError> {
-    let member_token = MemberToken::decode(&token)?;
+    let member_token = MemberToken::decode(&token).map_err(|e| {
+        warn!("{:?}", e);
+        ApplicationError::InvalidToken
+    })?;
 
     room_member_dao
         .find_room_member(&member_token.member_id, &member_token.secret_token)
         .await
 }
 
+/// Authenticates the member of a peer and checks how often it connects.
+///
+/// A peer that can't join is told why with an error message before the WebSocket is closed.
+///
+async fn admit_member(
+    token: String,
+    room_member_dao: RoomMemberDao,
+    access_policy: &AccessPolicy,
+    ws: &mut warp::ws::WebSocket,
+) -> Option<RoomMember> {
+    let (body, close) = match fetch_room_member(token, room_member_dao).await {
+        Ok(room_member) if access_policy.allows_connection_of_member(room_member.member_id) => {
+            return Some(room_member)
+        }
+        Ok(room_member) => {
+            warn!(
+                "Reject the member {} connecting too often.",
+                room_member.member_id
+            );
+            let body = ErrorBody::new(ErrorCode::RateLimited, "Too many connections.");
+            (body, access::RATE_LIMITED)
+        }
+        Err(e) => {
+            warn!("Reject the peer failing to authenticate {:?}.", e);
+            let close = match e.code() {
+                ErrorCode::Unauthorized => session::UNAUTHENTICATED,
+                ErrorCode::ServiceUnavailable => session::TRY_AGAIN_LATER,
+                _ => session::INTERNAL_ERROR,
+            };
+            (ErrorBody::from(&e), close)
+        }
+    };
+
+    if let Err(e) = reject_peer(ws, body, close).await {
+        error!("{:?} while rejecting the peer.", e);
+    }
+    None
+}
+
 /// Handles the upgrade request for Websocket and initializes RTCPeerConnection.
 ///
+/// The signaling protocol version is selected here, and a peer speaking only older versions
+/// than the SFU accepts is told so with an error message before the WebSocket is closed.
+/// The peer is then authenticated with the credentials in 'Sec-WebSocket-Protocol'
+/// or in its first message.
+///
 async fn handle_peer(
-    token: String,
-    ws: warp::ws::WebSocket,
-    peer_manager: PeerManagerRef,
+    credentials: Credentials,
+    query: ConnectQuery,
+    mut ws: warp::ws::WebSocket,
+    rooms: RoomRegistryRef,
+    rtc_api: RtcApiRef,
     room_member_dao: RoomMemberDao,
+    access_policy: AccessPolicyRef,
 ) {
-    if let Err(e) = handle_peer_delegate(token, ws, peer_manager, room_member_dao).await {
+    let protocol_version = match protocol::select_version(query.protocol_version) {
+        Some(version) => version,
+        None => {
+            warn!(
+                "Reject the peer speaking the protocol version {:?}.",
+                query.protocol_version
+            );
+            if let Err(e) = reject_protocol_version(&mut ws).await {
+                error!("{:?} while rejecting the protocol version.", e);
+            }
+            return;
+        }
+    };
+    let token = match credentials.authenticate(&mut ws).await {
+        Some(Credentials {
+            token: Some(token), ..
+        }) => token,
+        _ => return,
+    };
+    let room_member = match admit_member(token, room_member_dao, &access_policy, &mut ws).await {
+        Some(room_member) => room_member,
+        None => return,
+    };
+
+    if let Err(e) = handle_peer_delegate(
+        room_member,
+        protocol_version,
+        ws,
+        rooms,
+        rtc_api,
+        access_policy,
+    )
+    .await
+    {
         error!("Error on handle_subscribe {:?}.", e);
     }
 }
 
-async fn handle_peer_delegate(
-    token: String,
-    ws: warp::ws::WebSocket,
-    peer_manager: PeerManagerRef,
+/// Handles the upgrade request for Websocket reconnected with a resume token
+/// and hands it to the existing session of the peer.
+///
+async fn resume_peer(
+    credentials: Credentials,
+    mut ws: warp::ws::WebSocket,
+    rooms: RoomRegistryRef,
     room_member_dao: RoomMemberDao,
-) -> Result<(), ApplicationError> {
-    let room_member = fetch_room_member(token, room_member_dao).await?;
+    access_policy: AccessPolicyRef,
+) {
+    let (token, resume_token) = match credentials.authenticate(&mut ws).await {
+        Some(Credentials {
+            token: Some(token),
+            resume_token: Some(resume_token),
+            ..
+        }) => (token, resume_token),
+        Some(_) => {
+            warn!("The peer resuming its session has given no resume token.");
+            let (code, reason) = session::RESUME_FAILED;
+            if let Err(e) = ws.send(warp::ws::Message::close_with(code, reason)).await {
+                error!("{:?} while rejecting resumption.", e);
+            }
+            return;
+        }
+        None => return,
+    };
 
-    let peer_id = Uuid::new_v4();
+    let room_member = match admit_member(token, room_member_dao, &access_policy, &mut ws).await {
+        Some(room_member) => room_member,
+        None => return,
+    };
 
-    let (mut tx_ws, mut rx_ws) = ws.split();
-    let (tx_ws_facade, rx_ws_facade) = unbounded_channel();
-    let mut rx_ws_facade: UnboundedReceiverStream<warp::ws::Message> =
-        UnboundedReceiverStream::new(rx_ws_facade);
+    let member_id = room_member.member_id;
+    let attached = match rooms.get(room_member.room_id) {
+        Some(room) => {
+            room.ask(move |peer_manager| {
+                peer_manager.attach_to_session(&resume_token, member_id, Box::new(ws))
+            })
+            .await
+        }
+        None => Some(Err(Box::new(ws))),
+    };
 
-    let (tx_main_to_subscriber, rx_main_to_subscriber) = unbounded_channel();
-    let mut rx_main_to_subscriber: UnboundedReceiverStream<SubscriberMessage> =
-        UnboundedReceiverStream::new(rx_main_to_subscriber);
+    match attached {
+        Some(Ok(peer_id)) => info!("The session of {:?} is being resumed.", peer_id),
+        None => error!(
+            "The room {} has closed while resuming.",
+            room_member.room_id
+        ),
+        Some(Err(mut ws)) => {
+            warn!(
+                "The session of the member {} can't be resumed.",
+                room_member.member_id
+            );
+            let (code, reason) = session::RESUME_FAILED;
+            if let Err(e) = ws.send(warp::ws::Message::close_with(code, reason)).await {
+                error!("{:?} while rejecting resumption.", e);
+            }
+        }
+    }
+}
 
-    let (tx_main_to_publisher, rx_main_to_publisher) = unbounded_channel();
-    let mut rx_main_to_publisher: UnboundedReceiverStream<MessageToPublisher> =
-        UnboundedReceiverStream::new(rx_main_to_publisher);
+async fn reject_protocol_version(ws: &mut warp::ws::WebSocket) -> Result<(), ApplicationError> {
+    let message = format!(
+        "The SFU accepts the protocol version {} or newer. Please upgrade the client.",
+        protocol::MIN_PROTOCOL_VERSION
+    );
+    reject_peer(
+        ws,
+        ErrorBody::new(ErrorCode::UnsupportedProtocolVersion, &message),
+        protocol::UNSUPPORTED_PROTOCOL_VERSION,
+    )
+    .await
+}
 
-    let (tx_data_to_subscriber, rx_data_to_subscriber) = unbounded_channel();
-    let rx_data_to_subscriber: UnboundedReceiverStream<ToSubscriberDataChannelMessage> =
-        UnboundedReceiverStream::new(rx_data_to_subscriber);
-    {
-        let mut peer_manager = peer_manager.lock().unwrap();
-        peer_manager.add_peer(
-            &peer_id,
-            room_member,
-            tx_main_to_publisher,
-            tx_main_to_subscriber.clone(),
-            tx_data_to_subscriber.clone(),
-        );
+/// Asks the publisher for a keyframe of its video.
+///
+async fn request_keyframe(
+    pc: &RTCPeerConnection,
+    ssrc: u32,
+    request: KeyframeRequest,
+    fir_sequence_number: &mut u8,
+) -> Result<usize, webrtc::Error> {
+    match request {
+        KeyframeRequest::Pli => {
+            pc.write_rtcp(&[Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc: ssrc,
+            })])
+            .await
+        }
+        KeyframeRequest::Fir => {
+            *fir_sequence_number = fir_sequence_number.wrapping_add(1);
+            pc.write_rtcp(&[Box::new(FullIntraRequest {
+                sender_ssrc: 0,
+                media_ssrc: ssrc,
+                fir: vec![FirEntry {
+                    ssrc,
+                    sequence_number: *fir_sequence_number,
+                }],
+            })])
+            .await
+        }
     }
+}
+
+/// Sends the error to the peer and closes the WebSocket with the code and reason.
+///
+async fn reject_peer(
+    ws: &mut warp::ws::WebSocket,
+    body: ErrorBody,
+    (code, reason): (u16, &'static str),
+) -> Result<(), ApplicationError> {
+    ws.send(ServerMessage::Error(body).to_ws_message()?).await?;
+    ws.send(warp::ws::Message::close_with(code, reason)).await?;
+
+    Ok(())
+}
+
+async fn handle_peer_delegate(
+    room_member: RoomMember,
+    protocol_version: u16,
+    ws: warp::ws::WebSocket,
+    rooms: RoomRegistryRef,
+    rtc_api: RtcApiRef,
+    access_policy: AccessPolicyRef,
+) -> Result<(), ApplicationError> {
+    let member_id = room_member.member_id;
+
+    let peer_id = Uuid::new_v4();
+    let liveness = LivenessConfig::from_env();
+
+    let (tx_teardown, mut rx_teardown) = unbounded_channel::<TeardownReason>();
 
     //
-    // Send messages through websocket connection to the peer.
+    // The queues to the peer are bounded. Obsolete avatar moves are dropped when the data
+    // channel falls behind, and the peer is evicted when it falls behind on signaling.
     //
-    tokio::spawn(async move {
-        while let Some(msg) = rx_ws_facade.next().await {
-            if let Err(e) = tx_ws.send(msg).await {
-                error!("{:?} on {:?}.", e, peer_id);
-            }
-        }
-    });
+    let queues = QueueConfig::from_env();
+    let mut queue_metrics = QueueMetrics::default();
+    let (mut tx_ws, rx_ws) = ws.split();
+    let (tx_ws_facade, mut rx_ws_facade) = queue::channel(
+        "ws",
+        &peer_id,
+        queues.ws_capacity,
+        Overflow::Evict(tx_teardown.clone()),
+    );
+    queue_metrics.watch(&tx_ws_facade);
+    handler::send_to_peer(
+        &ServerMessage::Hello(HelloMessage { protocol_version }),
+        &tx_ws_facade,
+    )?;
+
+    let (tx_main_to_subscriber, mut rx_main_to_subscriber) = queue::channel(
+        "signaling",
+        &peer_id,
+        queues.signaling_capacity,
+        Overflow::Evict(tx_teardown.clone()),
+    );
+    queue_metrics.watch(&tx_main_to_subscriber);
+
+    // A dropped PLI, FIR or receiver report is made up for by the next one.
+    // Keyframe requests are further coalesced so that at most one reaches the publisher
+    // per interval however many subscribers ask for one at once.
+    let keyframe_request_interval =
+        Duration::from_millis(config::env_or("KEYFRAME_REQUEST_MIN_INTERVAL_MILLIS", 500));
+    let (tx_main_to_publisher, mut rx_main_to_publisher) = queue::channel(
+        "publisher",
+        &peer_id,
+        queues.publisher_capacity,
+        Overflow::DropNewest,
+    );
+    queue_metrics.watch(&tx_main_to_publisher);
+
+    let (tx_data_to_subscriber, rx_data_to_subscriber) = queue::channel(
+        "data",
+        &peer_id,
+        queues.data_capacity,
+        Overflow::DropOldest(
+            ToSubscriberDataChannelMessage::is_avatar_move,
+            tx_teardown.clone(),
+        ),
+    );
+    queue_metrics.watch(&tx_data_to_subscriber);
+
+    let (tx_attach, mut rx_attach) = unbounded_channel::<warp::ws::WebSocket>();
+    let (tx_ws_sink, mut rx_ws_sink) =
+        unbounded_channel::<SplitSink<warp::ws::WebSocket, warp::ws::Message>>();
 
-    let peer_connection = Arc::new(new_base_peer_connection().await?);
+    let peer_connection = Arc::new(rtc_api.new_peer_connection().await?);
+    let codec_policy = rtc_api.codec_policy().for_room(
+        room_member.video_codecs.as_deref(),
+        room_member.audio_codecs.as_deref(),
+    );
 
     peer_connection
         .add_transceiver_from_kind(RTPCodecType::Video, &[])
@@ -277,6 +670,101 @@ async fn handle_peer_delegate(
     peer_connection
         .add_transceiver_from_kind(RTPCodecType::Audio, &[])
         .await?;
+    codec_policy.apply(&peer_connection).await?;
+
+    //
+    // Create a data channel
+    //
+    let data_channel = peer_connection
+        .create_data_channel(&format!("sfu-data-ch-{}", peer_id), None)
+        .await?;
+
+    //
+    // The peer is in the room while the membership is held.
+    // From here on, the peer must leave through 'teardown_peer'.
+    //
+    let membership = rooms.join(room_member.room_id);
+    let room = membership.room().clone();
+    let tx_main_for_room = tx_main_to_subscriber.clone();
+    let tx_data_for_room = tx_data_to_subscriber.clone();
+    let tx_teardown_for_room = tx_teardown.clone();
+    let admitted = room
+        .ask(move |peer_manager| {
+            if !peer_manager.admit(&room_member) {
+                return false;
+            }
+            peer_manager.add_peer(
+                &peer_id,
+                room_member,
+                tx_main_to_publisher,
+                tx_main_for_room,
+                tx_data_for_room,
+                tx_teardown_for_room,
+            );
+            if !liveness.resume_grace_period.is_zero() {
+                peer_manager.set_resume_handle(
+                    &peer_id,
+                    ResumeHandle::new(member_id, liveness.resume_grace_period, tx_attach),
+                );
+            }
+            true
+        })
+        .await
+        .unwrap_or(false);
+    if !admitted {
+        warn!("Reject the duplicate session of the member {}.", member_id);
+        let (code, reason) = session::DUPLICATE_SESSION;
+        if let Err(e) = tx_ws
+            .send(warp::ws::Message::close_with(code, reason))
+            .await
+        {
+            error!("{:?} on {:?}.", e, peer_id);
+        }
+        peer_connection.close().await?;
+        return Ok(());
+    }
+    let mut tasks = vec![];
+    let activity_monitor = Arc::new(ActivityMonitor::new());
+
+    //
+    // Send messages through websocket connection to the peer.
+    // While the connection is lost, the messages are kept until the peer resumes.
+    // A peer speaking version 1 receives them rewritten, and only those it knows.
+    //
+    let ws_task = tokio::spawn(async move {
+        let mut tx_ws = Some(tx_ws);
+        loop {
+            let msg = tokio::select! {
+                Some(new_tx_ws) = rx_ws_sink.recv() => {
+                    tx_ws = Some(new_tx_ws);
+                    continue;
+                }
+                msg = rx_ws_facade.recv(), if tx_ws.is_some() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                else => break,
+            };
+            let msg = if protocol_version == 1 {
+                match protocol::to_legacy_message(msg) {
+                    Some(msg) => msg,
+                    None => continue,
+                }
+            } else {
+                msg
+            };
+            let is_close = msg.is_close();
+            if let Some(sink) = tx_ws.as_mut() {
+                if let Err(e) = sink.send(msg).await {
+                    error!("{:?} on {:?}.", e, peer_id);
+                    tx_ws = None;
+                }
+            }
+            if is_close {
+                break;
+            }
+        }
+    });
 
     let (local_track_chan_tx, mut local_track_chan_rx) =
         tokio::sync::mpsc::channel::<Arc<TrackLocalStaticRTP>>(2);
@@ -287,31 +775,34 @@ async fn handle_peer_delegate(
     let local_track_chan_tx = Arc::new(local_track_chan_tx);
     let track_ssrc_tx = Arc::new(track_ssrc_tx);
 
-    //
-    // Create a data channel
-    //
-    let data_channel = peer_connection
-        .create_data_channel(&format!("sfu-data-ch-{}", peer_id), None)
-        .await?;
     // Register channel opening handling
     let data_ch_for_open = Arc::clone(&data_channel);
+    let room_for_data_ch_open = room.clone();
     data_channel
         .on_open(Box::new(move || {
             info!("Data channel opens on {:?}.", peer_id);
 
             let data_ch_to_send = Arc::clone(&data_ch_for_open);
+            let room = room_for_data_ch_open.clone();
 
             Box::pin(async move {
-                handler::on_data_channel_open(&peer_id, data_ch_to_send, rx_data_to_subscriber)
-                    .await;
+                handler::on_data_channel_open(
+                    &peer_id,
+                    data_ch_to_send,
+                    rx_data_to_subscriber,
+                    room,
+                )
+                .await;
             })
         }))
         .await;
 
     // Register text message handling
-    let peer_manager_for_data_ch = peer_manager.clone();
+    let room_for_data_ch = room.clone();
+    let activity_monitor_for_data_ch = activity_monitor.clone();
     data_channel
         .on_message(Box::new(move |msg: DataChannelMessage| {
+            activity_monitor_for_data_ch.touch();
             let msg_str = match String::from_utf8(msg.data.to_vec()) {
                 Ok(msg) => msg,
                 Err(e) => {
@@ -319,8 +810,10 @@ async fn handle_peer_delegate(
                     return Box::pin(async {});
                 }
             };
-            let peer_manager = peer_manager_for_data_ch.lock().unwrap();
-            peer_manager.send_data_to_subscribers(&peer_id, msg_str);
+            room_for_data_ch.tell(move |peer_manager| {
+                peer_manager.observe_avatar_message(&peer_id, &msg_str);
+                peer_manager.send_data_to_subscribers(&peer_id, msg_str);
+            });
 
             Box::pin(async {})
         }))
@@ -328,6 +821,7 @@ async fn handle_peer_delegate(
     //
     // In order to publish a video and an audio, this pc should handle tracks from the client.
     //
+    let room_for_track = room.clone();
     peer_connection
         .on_track(Box::new(
             move |track: Option<Arc<TrackRemote>>, _receiver: Option<Arc<RTCRtpReceiver>>| {
@@ -336,6 +830,7 @@ async fn handle_peer_delegate(
                     track,
                     track_ssrc_tx.clone(),
                     local_track_chan_tx.clone(),
+                    room_for_track.clone(),
                 );
 
                 Box::pin(async {})
@@ -343,84 +838,111 @@ async fn handle_peer_delegate(
         ))
         .await;
 
-    let peer_manager_for_state_change = peer_manager.clone();
+    let tx_teardown_for_state_change = tx_teardown.clone();
     peer_connection
         .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
             handler::on_peer_connection_state_change(
                 s,
                 &peer_id,
-                peer_manager_for_state_change.clone(),
+                tx_teardown_for_state_change.clone(),
             );
 
             Box::pin(async {})
         }))
         .await;
 
-    let peer_manager_for_track_add = peer_manager.clone();
-    tokio::spawn(async move {
+    let room_for_track_add = room.clone();
+    tasks.push(tokio::spawn(async move {
         loop {
             if let Some(track) = local_track_chan_rx.recv().await {
-                let mut peer_manager = peer_manager_for_track_add.lock().unwrap();
-                peer_manager.add_track(&peer_id, track);
+                let started = room_for_track_add
+                    .ask(move |peer_manager| {
+                        peer_manager.add_track(&peer_id, track);
 
-                if peer_manager.has_both_audio_and_video(&peer_id) {
-                    peer_manager.send_to_subscribers(
-                        &peer_id,
-                        SubscriberMessage {
-                            msg_type: SubscriberMessageType::Start,
-                            message: String::from(""),
-                        },
-                    );
+                        let started = peer_manager.has_both_audio_and_video(&peer_id);
+                        if started {
+                            peer_manager.send_to_subscribers(&peer_id, SubscriberMessage::Start);
+                        }
+                        started
+                    })
+                    .await;
+                if started == Some(true) {
                     info!("Both audio and video track are added to {:?}.", peer_id);
                     break;
                 }
             }
         }
-    });
+    }));
 
     //
     // Forwards RTCP packets to the sender of the media stream.
     //
     let rtcp_observer_pc = peer_connection.clone();
-    tokio::spawn(async move {
+    let keyframe_request_metrics = KeyframeRequestMetrics::default();
+    let mut keyframe_requests =
+        KeyframeRequestThrottle::new(keyframe_request_interval, keyframe_request_metrics.clone());
+    tasks.push(tokio::spawn(async move {
         if let Some(ssrc) = track_ssrc_rx.recv().await {
             info!("SSRC {:?} detected on {:?}.", ssrc, peer_id);
 
-            while let Some(msg) = rx_main_to_publisher.next().await {
-                match msg {
-                    MessageToPublisher::RTCP(packet_type) => match packet_type {
-                        RTCPToPublisher::PLI => {
-                            if let Err(e) = rtcp_observer_pc
-                                .write_rtcp(&[Box::new(PictureLossIndication {
-                                    sender_ssrc: 0,
-                                    media_ssrc: ssrc,
-                                })])
-                                .await
-                            {
-                                error!("{:?} on {:?}.", e, peer_id);
+            // Incremented for each FIR so that the publisher tells new requests from repeated ones.
+            let mut fir_sequence_number: u8 = 0;
+            loop {
+                let request = tokio::select! {
+                    msg = rx_main_to_publisher.recv() => match msg {
+                        Some(MessageToPublisher::RTCP(packet_type)) => match packet_type {
+                            RTCPToPublisher::PLI => keyframe_requests
+                                .request(KeyframeRequest::Pli, tokio::time::Instant::now()),
+                            RTCPToPublisher::Fir => keyframe_requests
+                                .request(KeyframeRequest::Fir, tokio::time::Instant::now()),
+                            RTCPToPublisher::ReceiverReport(reception) => {
+                                if let Err(e) = rtcp_observer_pc
+                                    .write_rtcp(&[Box::new(ReceiverReport {
+                                        ssrc: 0,
+                                        reports: vec![reception.to_report(ssrc)],
+                                        ..Default::default()
+                                    })])
+                                    .await
+                                {
+                                    error!("{:?} on {:?}.", e, peer_id);
+                                }
+                                None
                             }
-                        }
+                        },
+                        None => break,
                     },
+                    _ = session::sleep_until(keyframe_requests.due_at()) => {
+                        keyframe_requests.take_due(tokio::time::Instant::now())
+                    }
+                };
+
+                if let Some(request) = request {
+                    if let Err(e) =
+                        request_keyframe(&rtcp_observer_pc, ssrc, request, &mut fir_sequence_number)
+                            .await
+                    {
+                        error!("{:?} on {:?}.", e, peer_id);
+                    }
                 }
             }
         }
-    });
+    }));
 
     //
     // Detect 'negotiation needed' events to send an offer.
     // Reference: https://developer.mozilla.org/en-US/docs/Web/API/WebRTC_API/Perfect_negotiation
     //
     let pc_for_renegotiation = peer_connection.clone();
-    let tx_ws_facade_for_renegotiation = tx_ws_facade.clone();
+    let tx_main_for_renegotiation = tx_main_to_subscriber.clone();
     peer_connection
         .on_negotiation_needed(Box::new(move || {
             let pc_for_renegotiation = pc_for_renegotiation.clone();
-            let tx_ws_facade_for_renegotiation = tx_ws_facade_for_renegotiation.clone();
+            let tx_main_for_renegotiation = tx_main_for_renegotiation.clone();
 
             handler::on_negotiation_needed(
                 &peer_id,
                 pc_for_renegotiation,
-                tx_ws_facade_for_renegotiation,
+                tx_main_for_renegotiation,
             );
 
             Box::pin(async {})
@@ -445,110 +967,299 @@ async fn handle_peer_delegate(
 
     //
     // The main event loop that handles messages for negotiation.
+    // Every negotiation of the peer runs here one at a time so that they never overlap,
+    // and the requested renegotiations are batched into as few offers as possible.
     //
-
-    tokio::spawn(async move {
-        while let Some(msg) = rx_main_to_subscriber.next().await {
-            let pc_for_prepare = peer_connection.clone();
-            let tx_ws_facade_for_prepare = tx_ws_facade.clone();
-            match msg.msg_type {
-                SubscriberMessageType::Prepare => {
-                    info!("Preparation is requested on {:?}.", peer_id);
-
-                    if let Err(e) =
-                        handler::do_offer(pc_for_prepare, tx_ws_facade_for_prepare).await
+    let negotiation_role = config::env_or("NEGOTIATION_ROLE", NegotiationRole::Polite);
+    let renegotiation_window =
+        Duration::from_millis(config::env_or("RENEGOTIATION_DEBOUNCE_MILLIS", 200));
+    let pc_for_teardown = peer_connection.clone();
+    let room_for_teardown = room.clone();
+    let tx_ws_facade_for_teardown = tx_ws_facade.clone();
+    tasks.push(tokio::spawn(async move {
+        let mut subscriber_senders = SubscriberSenders::new(codec_policy);
+        let mut renegotiation = Renegotiation::new(renegotiation_window);
+        loop {
+            let msg = tokio::select! {
+                msg = rx_main_to_subscriber.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = session::sleep_until(renegotiation.due_at()) => {
+                    if let Err(e) = handler::renegotiate(
+                        &peer_id,
+                        peer_connection.clone(),
+                        room.clone(),
+                        tx_ws_facade.clone(),
+                        &mut subscriber_senders,
+                        &mut renegotiation,
+                    )
+                    .await
                     {
                         error!("{:?} on {:?}.", e, peer_id);
                     }
+                    continue;
                 }
-                SubscriberMessageType::IceCandidate => {
-                    if let Err(e) =
-                        handler::handle_ice_candidate_message(&peer_id, &msg, pc_for_prepare).await
-                    {
-                        error!("{:?} on {:?}.", e, peer_id);
-                    }
+            };
+            let from_peer = matches!(msg, SubscriberMessage::Client(_));
+            let result = match msg {
+                SubscriberMessage::Client(ClientMessage::Prepare) => {
+                    info!("Preparation is requested on {:?}.", peer_id);
+                    renegotiation.request_offer(false);
+                    Ok(())
                 }
-                SubscriberMessageType::Answer => {
-                    if let Err(e) =
-                        handler::handle_answer_message(&peer_id, &msg, pc_for_prepare).await
-                    {
-                        error!("{:?} on {:?}", e, peer_id);
-                    }
+                SubscriberMessage::NegotiationNeeded => {
+                    renegotiation.request_offer(false);
+                    Ok(())
                 }
-                SubscriberMessageType::Start => {
-                    if let Err(e) = handler::handle_start_message(
+                SubscriberMessage::Client(ClientMessage::IceCandidate(candidate)) => {
+                    handler::handle_ice_candidate_message(
                         &peer_id,
-                        pc_for_prepare,
-                        peer_manager.clone(),
-                        tx_ws_facade_for_prepare,
+                        candidate,
+                        peer_connection.clone(),
                     )
                     .await
-                    {
-                        error!("{:?} on {:?}", e, peer_id);
+                }
+                SubscriberMessage::Client(ClientMessage::Answer(answer)) => {
+                    handler::handle_answer_message(
+                        &peer_id,
+                        answer,
+                        peer_connection.clone(),
+                        &room,
+                        &mut renegotiation,
+                    )
+                    .await
+                }
+                SubscriberMessage::Start => {
+                    renegotiation.request_sync();
+                    Ok(())
+                }
+                SubscriberMessage::UpdateForwarding => {
+                    handler::handle_update_forwarding_message(
+                        &peer_id,
+                        room.clone(),
+                        tx_ws_facade.clone(),
+                        &mut subscriber_senders,
+                    )
+                    .await
+                }
+                SubscriberMessage::Client(ClientMessage::Subscribe(subscription)) => {
+                    handler::handle_subscription_message(
+                        &peer_id,
+                        subscription,
+                        true,
+                        room.clone(),
+                        &mut renegotiation,
+                    );
+                    Ok(())
+                }
+                SubscriberMessage::Client(ClientMessage::Unsubscribe(subscription)) => {
+                    handler::handle_subscription_message(
+                        &peer_id,
+                        subscription,
+                        false,
+                        room.clone(),
+                        &mut renegotiation,
+                    );
+                    Ok(())
+                }
+                SubscriberMessage::Client(ClientMessage::Pin(pin)) => {
+                    handler::handle_pin_message(
+                        &peer_id,
+                        pin,
+                        room.clone(),
+                        tx_ws_facade.clone(),
+                        &mut subscriber_senders,
+                    )
+                    .await
+                }
+                SubscriberMessage::Client(ClientMessage::Ping) => {
+                    handler::handle_ping(tx_ws_facade.clone())
+                }
+                // Any message from the peer has already been recorded as its activity.
+                SubscriberMessage::Client(ClientMessage::Pong) => Ok(()),
+                SubscriberMessage::Client(ClientMessage::Authenticate(_)) => {
+                    warn!("{:?} has authenticated itself again.", peer_id);
+                    Ok(())
+                }
+                SubscriberMessage::Client(ClientMessage::RestartIce)
+                | SubscriberMessage::RestartIce => {
+                    handler::handle_restart_ice_message(
+                        &peer_id,
+                        peer_connection.clone(),
+                        &mut renegotiation,
+                    )
+                    .await
+                }
+                SubscriberMessage::Client(ClientMessage::Offer(offer)) => {
+                    handler::handle_offer_message(
+                        &peer_id,
+                        offer,
+                        peer_connection.clone(),
+                        &room,
+                        tx_ws_facade.clone(),
+                        negotiation_role,
+                        &mut renegotiation,
+                    )
+                    .await
+                }
+                SubscriberMessage::Client(ClientMessage::MediaState(media_state)) => {
+                    handler::handle_media_state_message(&peer_id, media_state, room.clone());
+                    Ok(())
+                }
+                SubscriberMessage::Relay(msg) => handler::send_to_peer(&msg, &tx_ws_facade),
+            };
+            if let Err(e) = result {
+                error!("{:?} on {:?}.", e, peer_id);
+                if from_peer {
+                    let msg = ServerMessage::Error(ErrorBody::from(&e));
+                    if let Err(e) = handler::send_to_peer(&msg, &tx_ws_facade) {
+                        error!("{:?} on {:?}.", e, peer_id);
                     }
                 }
-                SubscriberMessageType::Ping => {
-                    if let Err(e) = handler::handle_ping(tx_ws_facade_for_prepare) {
-                        error!("{:?} on {:?}", e, peer_id);
+            }
+        }
+    }));
+
+    //
+    // Reads messages from the peer while checking its liveness.
+    //
+    // When the WebSocket is lost, the peer is kept for the grace period
+    // so that it can resume its session on a new WebSocket.
+    //
+    let mut ping_interval = tokio::time::interval(liveness.ping_interval);
+    let mut rx_ws = Some(rx_ws);
+    let mut resume_deadline = None;
+    let reason = loop {
+        tokio::select! {
+            msg = session::next_message(&mut rx_ws) => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None if liveness.resume_grace_period.is_zero() => {
+                        break TeardownReason::WebSocketClosed
                     }
-                    continue;
+                    None => {
+                        info!(
+                            "WebSocket of {:?} has been lost so wait {:?} for it to resume.",
+                            peer_id, liveness.resume_grace_period
+                        );
+                        rx_ws = None;
+                        resume_deadline =
+                            Some(tokio::time::Instant::now() + liveness.resume_grace_period);
+                        continue;
+                    }
+                };
+                if activity_monitor.touch() {
+                    info!("{:?} has come back so ask it to resync.", peer_id);
+                    room_for_teardown.tell(move |peer_manager| peer_manager.resync(&peer_id));
                 }
-                SubscriberMessageType::Offer => {
-                    handle_unsupported_message_type(&msg.msg_type, &peer_id);
-                    continue;
+                match msg {
+                    Ok(msg) if msg.is_close() => break TeardownReason::WebSocketClosed,
+                    Ok(msg) if msg.is_ping() || msg.is_pong() => continue,
+                    _ => {}
                 }
-                SubscriberMessageType::Pong => {
-                    handle_unsupported_message_type(&msg.msg_type, &peer_id);
+                if !access_policy.allows_message_of_member(member_id) {
+                    warn!("{:?} has exceeded the message rate limit.", peer_id);
+                    let body = ErrorBody::new(ErrorCode::RateLimited, "Too many messages.");
+                    let msg = ServerMessage::Error(body);
+                    if let Err(e) = handler::send_to_peer(&msg, &tx_ws_facade_for_teardown) {
+                        error!("{:?} on {:?}.", e, peer_id);
+                    }
                     continue;
                 }
+                match parse_subscriber_message(msg, protocol_version) {
+                    Ok(msg) => {
+                        if let Err(e) = tx_main_to_subscriber.send(msg) {
+                            error!("{:?} on {:?}.", e, peer_id)
+                        }
+                    }
+                    Err(e) => {
+                        error!("{:?} on {:?}.", e, peer_id);
+                        let msg = ServerMessage::Error(ErrorBody::from(&e));
+                        if let Err(e) = handler::send_to_peer(&msg, &tx_ws_facade_for_teardown) {
+                            error!("{:?} on {:?}.", e, peer_id);
+                        }
+                    }
+                }
             }
-        }
-    });
-
-    while let Some(msg) = rx_ws.next().await {
-        match msg.map_err(ApplicationError::Web).and_then(|msg| {
-            msg.to_str().map_err(ApplicationError::Any).and_then(|s| {
-                serde_json::from_str::<SubscriberMessage>(&s).map_err(ApplicationError::Json)
-            })
-        }) {
-            Ok(msg) => {
-                if let Err(e) = tx_main_to_subscriber.send(msg) {
-                    error!("{:?} on {:?}.", e, peer_id)
+            Some(ws) = rx_attach.recv() => {
+                info!("{:?} resumes its session on a new WebSocket.", peer_id);
+                let (new_tx_ws, new_rx_ws) = ws.split();
+                if let Err(e) = tx_ws_sink.send(new_tx_ws) {
+                    error!("{:?} on {:?}.", e, peer_id);
                 }
+                rx_ws = Some(new_rx_ws);
+                resume_deadline = None;
+                activity_monitor.touch();
+                room_for_teardown.tell(move |peer_manager| peer_manager.resume(&peer_id));
             }
-            Err(e) => error!("{:?} on {:?}.", e, peer_id),
+            _ = ping_interval.tick() => {
+                if rx_ws.is_none() {
+                    continue;
+                }
+                let idle_for = activity_monitor.idle_for();
+                if idle_for >= liveness.idle_timeout {
+                    break TeardownReason::IdleTimeout;
+                }
+                if idle_for >= liveness.stale_after && activity_monitor.mark_stale() {
+                    warn!("{:?} has been inactive for {:?}.", peer_id, idle_for);
+                }
+                debug!(
+                    "The queues of {:?}: {}, {}.",
+                    peer_id, queue_metrics, keyframe_request_metrics
+                );
+                if let Err(e) = tx_ws_facade_for_teardown.send(warp::ws::Message::ping(vec![])) {
+                    error!("{:?} on {:?}.", e, peer_id);
+                }
+            }
+            _ = session::sleep_until(resume_deadline) => break TeardownReason::ResumeExpired,
+            Some(reason) = rx_teardown.recv() => match reason {
+                TeardownReason::ConnectionFailed if !liveness.resume_grace_period.is_zero() => {
+                    // A peer that has lost its WebSocket restarts ICE when it resumes.
+                    if rx_ws.is_some() {
+                        warn!("The connection of {:?} has failed so restart ICE.", peer_id);
+                        if let Err(e) = tx_main_to_subscriber.send(SubscriberMessage::RestartIce) {
+                            error!("{:?} on {:?}.", e, peer_id);
+                        }
+                    }
+                }
+                reason => break reason,
+            },
         }
+    };
+
+    if let TeardownReason::SlowConsumer = reason {
+        warn!(
+            "Evict {:?} as a slow consumer with the queues {}.",
+            peer_id, queue_metrics
+        );
     }
 
-    Ok(())
-}
+    handler::teardown_peer(
+        &peer_id,
+        reason,
+        pc_for_teardown,
+        room_for_teardown,
+        tx_ws_facade_for_teardown,
+        ws_task,
+        tasks,
+    )
+    .await;
 
-fn handle_unsupported_message_type(msg_type: &SubscriberMessageType, peer_id: &Uuid) {
-    error!(
-        "Receiving {:?} is currently not supported ({:?}).",
-        msg_type, peer_id
-    );
+    Ok(())
 }
 
-async fn new_base_peer_connection() -> Result<RTCPeerConnection, webrtc::Error> {
-    let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
-    let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut m)?;
-
-    let api = APIBuilder::new()
-        .with_media_engine(m)
-        .with_interceptor_registry(registry)
-        .build();
-
-    let ice_servers = ice::create_ice_server_config("sfu");
-    let config = RTCConfiguration {
-        ice_servers: ice_servers,
-        // ice_transport_policy: RTCIceTransportPolicy::Relay,
-        ..Default::default()
-    };
-
-    api.new_peer_connection(config).await
+fn parse_subscriber_message(
+    msg: Result<warp::ws::Message, warp::Error>,
+    protocol_version: u16,
+) -> Result<SubscriberMessage, ApplicationError> {
+    msg.map_err(ApplicationError::Web).and_then(|msg| {
+        msg.to_str().map_err(ApplicationError::Any).and_then(|s| {
+            ClientMessage::parse(s, protocol_version)
+                .map(SubscriberMessage::Client)
+                .map_err(ApplicationError::Json)
+        })
+    })
 }
 
 fn ok_with_json<T>(data: &T) -> warp::reply::WithStatus<warp::reply::Json>
@@ -558,17 +1269,38 @@ where
     warp::reply::with_status(warp::reply::json(data), StatusCode::OK)
 }
 
-fn resp_with_message(
-    message: String,
-    status_code: StatusCode,
-) -> warp::reply::WithStatus<warp::reply::Json> {
-    warp::reply::with_status(warp::reply::json(&MessageResponse { message }), status_code)
+fn error_reply(body: &ErrorBody) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(body), body.code.status())
 }
 
+/// Maps the rejections to error responses with the codes and statuses clients can act on.
+///
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
-    error!("handle_rejection {:?}", err);
-    Ok(warp::reply::with_status(
-        "Internal Server Error",
-        StatusCode::INTERNAL_SERVER_ERROR,
-    ))
+    let body = if err.find::<OriginNotAllowed>().is_some() || err.find::<CorsForbidden>().is_some()
+    {
+        ErrorBody::new(ErrorCode::Forbidden, "Origin not allowed.")
+    } else if err.find::<TooManyRequests>().is_some() {
+        ErrorBody::new(ErrorCode::RateLimited, "Too many requests.")
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        if e.name() == SECRET_HEADER_KEY {
+            ErrorBody::new(ErrorCode::Unauthorized, &e.to_string())
+        } else {
+            ErrorBody::new(ErrorCode::InvalidRequest, &e.to_string())
+        }
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        ErrorBody::new(ErrorCode::InvalidRequest, &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        ErrorBody::new(ErrorCode::InvalidRequest, &e.to_string())
+    } else if let Some(e) = err.find::<warp::ws::MissingConnectionUpgrade>() {
+        ErrorBody::new(ErrorCode::InvalidRequest, &e.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ErrorBody::new(ErrorCode::MethodNotAllowed, "Method not allowed.")
+    } else if err.is_not_found() {
+        ErrorBody::new(ErrorCode::NotFound, "Not found.")
+    } else {
+        error!("handle_rejection {:?}", err);
+        ErrorBody::new(ErrorCode::Internal, "Internal error.")
+    };
+
+    Ok(error_reply(&body))
 }
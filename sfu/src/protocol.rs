@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::ws::Message;
+
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+use crate::errors::ErrorBody;
+
+/// The newest version of the signaling protocol the SFU speaks.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The oldest version the SFU accepts.
+///
+/// Version 1 carries every payload as a JSON document in a string, and knows only
+/// the messages to negotiate the RTCPeerConnection. Its clients tell no version.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Closes the WebSocket of a peer speaking no version the SFU does.
+pub const UNSUPPORTED_PROTOCOL_VERSION: (u16, &str) = (4007, "unsupported_protocol_version");
+
+/// The query of the URL a peer connects to.
+///
+#[derive(Deserialize, Debug)]
+pub struct ConnectQuery {
+    /// The newest version of the signaling protocol the peer speaks.
+    pub protocol_version: Option<u16>,
+}
+
+/// Picks the version used with a peer from the newest one it speaks.
+///
+/// Peers telling no version are regarded as speaking version 1.
+/// Returns None if the version is older than the SFU accepts.
+///
+pub fn select_version(requested: Option<u16>) -> Option<u16> {
+    let version = requested.unwrap_or(1).min(PROTOCOL_VERSION);
+    if version >= MIN_PROTOCOL_VERSION {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// Messages a peer sends to the SFU.
+///
+/// Each message is a JSON object with the kind in 'type' and the body, if any, in 'payload'.
+///
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// The first message of a peer that hasn't given its credentials on the upgrade request.
+    Authenticate(AuthenticateMessage),
+    /// Asks the SFU for the first offer.
+    Prepare,
+    Offer(SessionDescription),
+    Answer(SessionDescription),
+    /// A null candidate means the end of the candidates.
+    IceCandidate(Option<IceCandidate>),
+    Ping,
+    Pong,
+    Pin(PinMessage),
+    Subscribe(SubscriptionMessage),
+    Unsubscribe(SubscriptionMessage),
+    MediaState(MediaState),
+    RestartIce,
+}
+
+/// Messages the SFU sends to a peer.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The first message on a connection, telling the version in use.
+    Hello(HelloMessage),
+    Offer(SessionDescription),
+    Answer(SessionDescription),
+    IceCandidate(IceCandidate),
+    Pong,
+    ActiveSpeaker(ActiveSpeakerMessage),
+    Forwarding(ForwardingMessage),
+    Slots(SlotsMessage),
+    Roster(RosterMessage),
+    PeerJoined(RosterEntry),
+    PeerUpdated(RosterEntry),
+    PeerLeft(PeerLeftMessage),
+    Session(SessionMessage),
+    /// Tells a peer that has come back from a stale state that its state is being resent.
+    Resync,
+    Evicted(EvictedMessage),
+    Error(ErrorBody),
+}
+
+impl ServerMessage {
+    pub fn to_ws_message(&self) -> Result<Message, serde_json::Error> {
+        Ok(Message::text(serde_json::to_string(self)?))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct HelloMessage {
+    pub protocol_version: u16,
+}
+
+/// An SDP in the form of 'RTCSessionDescriptionInit' in browsers.
+///
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub sdp_type: SdpType,
+    pub sdp: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SdpType {
+    Offer,
+    Pranswer,
+    Answer,
+    Rollback,
+}
+
+impl SessionDescription {
+    // 'RTCSessionDescription' is serialized in the same form.
+    pub fn from_rtc(description: &RTCSessionDescription) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(description)?)
+    }
+
+    pub fn into_rtc(self) -> Result<RTCSessionDescription, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(self)?)
+    }
+}
+
+/// An ICE candidate in the form of 'RTCIceCandidateInit' in browsers.
+///
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct IceCandidate {
+    pub candidate: Option<String>,
+    #[serde(rename = "sdpMid")]
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex")]
+    pub sdp_mline_index: Option<u16>,
+    #[serde(rename = "usernameFragment")]
+    pub username_fragment: Option<String>,
+}
+
+impl IceCandidate {
+    pub fn to_candidate_init(self) -> RTCIceCandidateInit {
+        RTCIceCandidateInit {
+            candidate: self.candidate.unwrap_or("".to_owned()),
+            sdp_mid: self.sdp_mid.unwrap_or("".to_owned()),
+            sdp_mline_index: self.sdp_mline_index.unwrap_or(0u16),
+            username_fragment: self.username_fragment.unwrap_or("".to_owned()),
+        }
+    }
+}
+
+impl From<RTCIceCandidateInit> for IceCandidate {
+    fn from(another: RTCIceCandidateInit) -> IceCandidate {
+        IceCandidate {
+            candidate: Some(another.candidate),
+            sdp_mid: Some(another.sdp_mid),
+            sdp_mline_index: Some(another.sdp_mline_index),
+            username_fragment: Some(another.username_fragment),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct ActiveSpeakerMessage {
+    /// None when the dominant speaker has left and nobody has taken over yet.
+    pub peer_id: Option<Uuid>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    pub fn all() -> HashSet<MediaKind> {
+        [MediaKind::Audio, MediaKind::Video].into_iter().collect()
+    }
+
+    pub fn of(kind: RTPCodecType) -> Option<MediaKind> {
+        match kind {
+            RTPCodecType::Audio => Some(MediaKind::Audio),
+            RTPCodecType::Video => Some(MediaKind::Video),
+            _ => None,
+        }
+    }
+}
+
+/// Every member joins a room with the same role for now.
+///
+#[derive(Serialize, Debug, Copy, Clone, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Member,
+}
+
+/// The body of 'media_state' messages with which remote peers tell whether they are muted.
+///
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, JsonSchema)]
+pub struct MediaState {
+    pub audio_muted: bool,
+    pub video_muted: bool,
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct RosterEntry {
+    pub peer_id: Uuid,
+    pub member_id: i64,
+    pub member_name: String,
+    pub role: MemberRole,
+    pub published_kinds: Vec<MediaKind>,
+    #[serde(flatten)]
+    pub media_state: MediaState,
+}
+
+/// The body of 'roster' messages. 'peer_id' is the id of the peer receiving the message.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct RosterMessage {
+    pub peer_id: Uuid,
+    pub members: Vec<RosterEntry>,
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct PeerLeftMessage {
+    pub peer_id: Uuid,
+}
+
+/// The body of 'evicted' messages, which carry the code and reason the WebSocket is closed with.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct EvictedMessage {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// The body of 'session' messages telling a peer how to resume its session.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct SessionMessage {
+    pub peer_id: Uuid,
+    pub resume_token: String,
+    pub resume_grace_period_secs: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct PausedTrack {
+    pub peer_id: Uuid,
+    pub kind: Option<MediaKind>,
+}
+
+/// The body of 'forwarding' messages telling a subscriber which tracks it doesn't receive.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct ForwardingMessage {
+    pub paused: Vec<PausedTrack>,
+    /// The tracks left out since the subscriber can't decode their codecs.
+    pub undecodable: Vec<PausedTrack>,
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct SlotEntry {
+    pub mid: String,
+    pub kind: Option<MediaKind>,
+    pub peer_id: Option<Uuid>,
+}
+
+/// The body of 'slots' messages telling a subscriber which publisher occupies each slot.
+/// A slot without 'peer_id' is free.
+///
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct SlotsMessage {
+    pub slots: Vec<SlotEntry>,
+}
+
+/// The body of 'authenticate' messages. 'resume_token' is given to resume a session.
+///
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct AuthenticateMessage {
+    pub token: String,
+    pub resume_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct PinMessage {
+    pub peer_ids: Vec<Uuid>,
+}
+
+/// The body of 'subscribe' and 'unsubscribe' messages.
+///
+/// Omitting 'peer_ids' means every publisher in the room including those joining later,
+/// and omitting 'kinds' means both audio and video.
+///
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct SubscriptionMessage {
+    pub peer_ids: Option<Vec<Uuid>>,
+    pub kinds: Option<Vec<MediaKind>>,
+}
+
+/// The root of the JSON Schema, listing the messages in both directions.
+///
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct SignalingProtocol {
+    client_message: ClientMessage,
+    server_message: ServerMessage,
+}
+
+/// The JSON Schema of the signaling messages, from which the client's types are generated.
+///
+pub fn json_schema() -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&schemars::schema_for!(SignalingProtocol))
+}
+
+/// The kinds of the messages of version 1.
+///
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+enum LegacyMessageType {
+    Prepare,
+    Start,
+    Offer,
+    Answer,
+    IceCandidate,
+    Ping,
+    Pong,
+}
+
+/// A message of version 1, whose body is a JSON document in 'message'.
+///
+#[derive(Deserialize, Serialize, Debug)]
+struct LegacyMessage {
+    msg_type: LegacyMessageType,
+    #[serde(default)]
+    message: String,
+}
+
+impl ClientMessage {
+    /// Parses a message a peer sends in the version in use.
+    ///
+    pub fn parse(text: &str, protocol_version: u16) -> Result<Self, serde_json::Error> {
+        if protocol_version > 1 {
+            return serde_json::from_str(text);
+        }
+
+        let legacy = serde_json::from_str::<LegacyMessage>(text)?;
+        Ok(match legacy.msg_type {
+            LegacyMessageType::Prepare => ClientMessage::Prepare,
+            LegacyMessageType::Offer => {
+                ClientMessage::Offer(serde_json::from_str(&legacy.message)?)
+            }
+            LegacyMessageType::Answer => {
+                ClientMessage::Answer(serde_json::from_str(&legacy.message)?)
+            }
+            LegacyMessageType::IceCandidate => {
+                ClientMessage::IceCandidate(serde_json::from_str(&legacy.message)?)
+            }
+            LegacyMessageType::Ping => ClientMessage::Ping,
+            LegacyMessageType::Pong => ClientMessage::Pong,
+            LegacyMessageType::Start => {
+                return Err(serde::de::Error::custom("'Start' is not sent by peers"))
+            }
+        })
+    }
+}
+
+/// Rewrites a message of the current version for a peer speaking version 1.
+///
+/// Returns None for the messages version 1 doesn't know, which the peer does without.
+/// Messages other than text, e.g. the close frame, are left as they are.
+///
+pub fn to_legacy_message(msg: Message) -> Option<Message> {
+    let text = match msg.to_str() {
+        Ok(text) => text,
+        Err(_) => return Some(msg),
+    };
+    let value = serde_json::from_str::<serde_json::Value>(text).ok()?;
+    let msg_type = match value.get("type")?.as_str()? {
+        "offer" => LegacyMessageType::Offer,
+        "answer" => LegacyMessageType::Answer,
+        "ice_candidate" => LegacyMessageType::IceCandidate,
+        "pong" => LegacyMessageType::Pong,
+        _ => return None,
+    };
+    let message = value
+        .get("payload")
+        .map(|payload| payload.to_string())
+        .unwrap_or_default();
+    let legacy = serde_json::to_string(&LegacyMessage { msg_type, message }).ok()?;
+    Some(Message::text(legacy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_version_is_version_1() {
+        assert_eq!(select_version(None), Some(1));
+        assert_eq!(select_version(Some(2)), Some(2));
+        assert_eq!(select_version(Some(3)), Some(PROTOCOL_VERSION));
+        assert_eq!(select_version(Some(0)), None);
+    }
+
+    #[test]
+    fn parses_legacy_messages() {
+        let prepare = r#"{"msg_type":"Prepare","message":""}"#;
+        assert!(matches!(
+            ClientMessage::parse(prepare, 1),
+            Ok(ClientMessage::Prepare)
+        ));
+        assert!(ClientMessage::parse(prepare, 2).is_err());
+
+        let answer = r#"{"msg_type":"Answer","message":"{\"type\":\"answer\",\"sdp\":\"v=0\"}"}"#;
+        match ClientMessage::parse(answer, 1) {
+            Ok(ClientMessage::Answer(answer)) => assert_eq!(answer.sdp, "v=0"),
+            other => panic!("{:?}", other),
+        }
+
+        let candidate = r#"{"msg_type":"IceCandidate","message":"{\"candidate\":\"candidate:1\",\"sdpMid\":\"0\"}"}"#;
+        match ClientMessage::parse(candidate, 1) {
+            Ok(ClientMessage::IceCandidate(Some(candidate))) => {
+                assert_eq!(candidate.sdp_mid.as_deref(), Some("0"))
+            }
+            other => panic!("{:?}", other),
+        }
+        let end_of_candidates = r#"{"msg_type":"IceCandidate","message":"null"}"#;
+        assert!(matches!(
+            ClientMessage::parse(end_of_candidates, 1),
+            Ok(ClientMessage::IceCandidate(None))
+        ));
+
+        assert!(ClientMessage::parse(r#"{"msg_type":"Start"}"#, 1).is_err());
+    }
+
+    #[test]
+    fn rewrites_messages_for_version_1() {
+        let offer = ServerMessage::Offer(SessionDescription {
+            sdp_type: SdpType::Offer,
+            sdp: "v=0".to_owned(),
+        });
+        let legacy = to_legacy_message(offer.to_ws_message().unwrap()).unwrap();
+        let legacy = serde_json::from_str::<LegacyMessage>(legacy.to_str().unwrap()).unwrap();
+        assert_eq!(legacy.msg_type, LegacyMessageType::Offer);
+        let description = serde_json::from_str::<SessionDescription>(&legacy.message).unwrap();
+        assert_eq!(description.sdp, "v=0");
+
+        let pong = to_legacy_message(ServerMessage::Pong.to_ws_message().unwrap()).unwrap();
+        assert!(pong.to_str().unwrap().contains("\"Pong\""));
+
+        let hello = ServerMessage::Hello(HelloMessage {
+            protocol_version: 1,
+        });
+        assert!(to_legacy_message(hello.to_ws_message().unwrap()).is_none());
+
+        let close = Message::close();
+        assert!(to_legacy_message(close).unwrap().is_close());
+    }
+}
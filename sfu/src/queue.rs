@@ -0,0 +1,411 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use log::{debug, warn};
+
+use crate::config;
+use crate::handler::TeardownReason;
+
+/// The capacities of the queues of each peer.
+///
+#[derive(Debug, Copy, Clone)]
+pub struct QueueConfig {
+    /// The messages waiting to be written to the WebSocket.
+    pub ws_capacity: usize,
+    /// The messages waiting for the event loop of the peer.
+    pub signaling_capacity: usize,
+    /// The RTCP requests waiting to be sent to the peer as a publisher.
+    pub publisher_capacity: usize,
+    /// The messages waiting to be sent through the data channel.
+    pub data_capacity: usize,
+}
+
+impl QueueConfig {
+    pub fn from_env() -> Self {
+        QueueConfig {
+            ws_capacity: config::env_or("WS_QUEUE_CAPACITY", 256),
+            signaling_capacity: config::env_or("SIGNALING_QUEUE_CAPACITY", 256),
+            publisher_capacity: config::env_or("PUBLISHER_QUEUE_CAPACITY", 16),
+            data_capacity: config::env_or("DATA_QUEUE_CAPACITY", 256),
+        }
+    }
+}
+
+/// What a full queue does with a new message.
+///
+pub enum Overflow<T> {
+    /// The new message is dropped, for messages the next one makes up for.
+    DropNewest,
+    /// The oldest of the messages the function accepts, including the new one, is dropped.
+    /// The peer is evicted as a slow consumer if it accepts none of them.
+    DropOldest(fn(&T) -> bool, UnboundedSender<TeardownReason>),
+    /// The peer is evicted as a slow consumer.
+    Evict(UnboundedSender<TeardownReason>),
+}
+
+/// Why a message isn't queued. The queue logs its name when it evicts the peer.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum QueueError {
+    /// The queue is full and the peer is being evicted.
+    Full,
+    /// The receiver has gone.
+    Closed,
+}
+
+struct Shared<T> {
+    name: &'static str,
+    peer_id: Uuid,
+    capacity: usize,
+    queue: Mutex<VecDeque<T>>,
+    notify: Notify,
+    senders: AtomicUsize,
+    receiving: AtomicBool,
+    dropped: AtomicUsize,
+    high_water: AtomicUsize,
+}
+
+impl<T> Shared<T> {
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            name: self.name,
+            depth: self.queue.lock().unwrap().len(),
+            capacity: self.capacity,
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Creates a bounded queue of messages to a peer, which applies the policy when it is full.
+///
+/// Unlike the channels of tokio, a sender never waits, so the room and the tasks of other peers
+/// are never held up by a peer that consumes its messages slowly.
+///
+pub fn channel<T>(
+    name: &'static str,
+    peer_id: &Uuid,
+    capacity: usize,
+    overflow: Overflow<T>,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        name,
+        peer_id: peer_id.clone(),
+        capacity: capacity.max(1),
+        queue: Mutex::new(VecDeque::new()),
+        notify: Notify::new(),
+        senders: AtomicUsize::new(1),
+        receiving: AtomicBool::new(true),
+        dropped: AtomicUsize::new(0),
+        high_water: AtomicUsize::new(0),
+    });
+    (
+        QueueSender {
+            shared: Arc::clone(&shared),
+            overflow: Arc::new(overflow),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+    overflow: Arc<Overflow<T>>,
+}
+
+impl<T> QueueSender<T> {
+    pub fn send(&self, message: T) -> Result<(), QueueError> {
+        let shared = &self.shared;
+        if !shared.receiving.load(Ordering::Acquire) {
+            return Err(QueueError::Closed);
+        }
+
+        {
+            let mut queue = shared.queue.lock().unwrap();
+            if queue.len() >= shared.capacity {
+                let evicted_by = match self.overflow.as_ref() {
+                    Overflow::DropNewest => {
+                        self.on_dropped();
+                        return Ok(());
+                    }
+                    Overflow::DropOldest(droppable, tx_teardown) => {
+                        if let Some(position) = queue.iter().position(droppable) {
+                            queue.remove(position);
+                            self.on_dropped();
+                            None
+                        } else if droppable(&message) {
+                            self.on_dropped();
+                            return Ok(());
+                        } else {
+                            Some(tx_teardown)
+                        }
+                    }
+                    Overflow::Evict(tx_teardown) => Some(tx_teardown),
+                };
+                if let Some(tx_teardown) = evicted_by {
+                    drop(queue);
+                    self.evict(tx_teardown);
+                    return Err(QueueError::Full);
+                }
+            }
+            queue.push_back(message);
+            shared.high_water.fetch_max(queue.len(), Ordering::Relaxed);
+        }
+        shared.notify.notify_one();
+
+        Ok(())
+    }
+
+    /// Drops the messages waiting in the queue and returns how many they were.
+    ///
+    pub fn clear(&self) -> usize {
+        let cleared = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            let cleared = queue.len();
+            queue.clear();
+            cleared
+        };
+        self.shared.dropped.fetch_add(cleared, Ordering::Relaxed);
+        cleared
+    }
+
+    fn on_dropped(&self) {
+        let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(
+            "The {} queue of {:?} is full and has dropped {} messages.",
+            self.shared.name, self.shared.peer_id, dropped
+        );
+    }
+
+    fn evict(&self, tx_teardown: &UnboundedSender<TeardownReason>) {
+        warn!(
+            "The {} queue of {:?} is full so evict it as a slow consumer.",
+            self.shared.name, self.shared.peer_id
+        );
+        // The receiver has gone if the teardown has already started.
+        let _ = tx_teardown.send(TeardownReason::SlowConsumer);
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        QueueSender {
+            shared: Arc::clone(&self.shared),
+            overflow: Arc::clone(&self.overflow),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Receives the next message, or None once every sender has been dropped.
+    ///
+    /// Cancelling the returned future loses no message.
+    ///
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(message) = queue.pop_front() {
+                    return Some(message);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiving.store(false, Ordering::Release);
+        self.shared.queue.lock().unwrap().clear();
+    }
+}
+
+/// A snapshot of the depth of a queue and how many messages it has dropped.
+///
+#[derive(Debug, Clone)]
+pub struct QueueStats {
+    pub name: &'static str,
+    pub depth: usize,
+    pub capacity: usize,
+    /// The deepest the queue has been.
+    pub high_water: usize,
+    pub dropped: usize,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}/{} (max {}, {} dropped)",
+            self.name, self.depth, self.capacity, self.high_water, self.dropped
+        )
+    }
+}
+
+/// The queues of a peer, whose depths are reported as the metrics of the peer.
+///
+#[derive(Default)]
+pub struct QueueMetrics {
+    probes: Vec<Box<dyn Fn() -> QueueStats + Send + Sync>>,
+}
+
+impl QueueMetrics {
+    /// Adds the queue of the sender to the metrics without keeping the queue open.
+    ///
+    pub fn watch<T: Send + 'static>(&mut self, sender: &QueueSender<T>) {
+        let shared = Arc::clone(&sender.shared);
+        self.probes.push(Box::new(move || shared.stats()));
+    }
+
+    pub fn snapshot(&self) -> Vec<QueueStats> {
+        self.probes.iter().map(|probe| probe()).collect()
+    }
+}
+
+impl fmt::Display for QueueMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats: Vec<String> = self.snapshot().iter().map(|s| s.to_string()).collect();
+        write!(f, "{}", stats.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn is_even(message: &u32) -> bool {
+        message % 2 == 0
+    }
+
+    async fn recv_all(rx: &mut QueueReceiver<u32>) -> Vec<u32> {
+        let mut messages = vec![];
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::ZERO, rx.recv()).await {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn drop_newest_drops_new_messages_when_full() {
+        let (tx, mut rx) = channel("test", &Uuid::new_v4(), 2, Overflow::DropNewest);
+        for message in 1..=3 {
+            assert!(tx.send(message).is_ok());
+        }
+
+        assert_eq!(recv_all(&mut rx).await, vec![1, 2]);
+        assert_eq!(tx.shared.stats().dropped, 1);
+        assert_eq!(tx.shared.stats().high_water, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_drops_only_droppable_messages() {
+        let (tx_teardown, mut rx_teardown) = unbounded_channel();
+        let (tx, mut rx) = channel(
+            "test",
+            &Uuid::new_v4(),
+            3,
+            Overflow::DropOldest(is_even, tx_teardown),
+        );
+        for message in [1, 2, 3] {
+            assert!(tx.send(message).is_ok());
+        }
+
+        // The oldest droppable message makes room for the new one.
+        assert!(tx.send(5).is_ok());
+        // The new message is dropped when it is the only droppable one.
+        assert!(tx.send(4).is_ok());
+        assert!(rx_teardown.try_recv().is_err());
+        assert_eq!(tx.shared.stats().dropped, 2);
+
+        // The peer is evicted when none of them is droppable.
+        assert_eq!(tx.send(7), Err(QueueError::Full));
+        assert!(matches!(
+            rx_teardown.try_recv(),
+            Ok(TeardownReason::SlowConsumer)
+        ));
+
+        assert_eq!(recv_all(&mut rx).await, vec![1, 3, 5]);
+    }
+
+    #[tokio::test]
+    async fn evict_evicts_peer_when_full() {
+        let (tx_teardown, mut rx_teardown) = unbounded_channel();
+        let (tx, mut rx) = channel("test", &Uuid::new_v4(), 1, Overflow::Evict(tx_teardown));
+        assert!(tx.send(1).is_ok());
+        assert_eq!(tx.send(2), Err(QueueError::Full));
+        assert!(matches!(
+            rx_teardown.try_recv(),
+            Ok(TeardownReason::SlowConsumer)
+        ));
+
+        assert_eq!(recv_all(&mut rx).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn recv_returns_none_after_last_sender_drops() {
+        let (tx, mut rx) = channel("test", &Uuid::new_v4(), 2, Overflow::DropNewest);
+        let another_tx = tx.clone();
+        assert!(tx.send(1).is_ok());
+        drop(tx);
+
+        // The messages queued before are still received.
+        assert_eq!(rx.recv().await, Some(1));
+
+        let receiving = tokio::spawn(async move { rx.recv().await });
+        tokio::task::yield_now().await;
+        drop(another_tx);
+        let received = tokio::time::timeout(Duration::from_secs(1), receiving)
+            .await
+            .expect("recv should wake up when the last sender drops");
+        assert_eq!(received.unwrap(), None);
+    }
+
+    #[test]
+    fn send_fails_after_receiver_drops() {
+        let (tx, rx) = channel("test", &Uuid::new_v4(), 2, Overflow::DropNewest);
+        assert!(tx.send(1).is_ok());
+        drop(rx);
+
+        assert_eq!(tx.send(2), Err(QueueError::Closed));
+        assert_eq!(tx.shared.stats().depth, 0);
+    }
+
+    #[tokio::test]
+    async fn clear_drops_waiting_messages() {
+        let (tx, mut rx) = channel("test", &Uuid::new_v4(), 2, Overflow::DropNewest);
+        assert!(tx.send(1).is_ok());
+        assert!(tx.send(2).is_ok());
+
+        assert_eq!(tx.clear(), 2);
+        assert_eq!(tx.shared.stats().dropped, 2);
+
+        // The queue has room again, e.g. for a close frame.
+        assert!(tx.send(3).is_ok());
+        assert_eq!(recv_all(&mut rx).await, vec![3]);
+    }
+}
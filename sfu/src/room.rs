@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use log::{info, warn};
+
+use crate::config;
+use crate::handler::PeerManager;
+use crate::session::DuplicateSessionPolicy;
+
+type RoomTask = Box<dyn FnOnce(&mut PeerManager) + Send>;
+
+/// Settings shared by every room, which members' rows in the database may override.
+///
+#[derive(Debug, Copy, Clone)]
+pub struct RoomConfig {
+    pub default_last_n: usize,
+    pub proximity_distance: f64,
+    pub default_duplicate_session_policy: DuplicateSessionPolicy,
+    /// The interval at which a publisher is told the reception aggregated across its subscribers.
+    pub receiver_report_interval: Duration,
+}
+
+impl RoomConfig {
+    pub fn from_env() -> Self {
+        RoomConfig {
+            default_last_n: config::env_or("LAST_N", 0),
+            proximity_distance: config::env_or("PROXIMITY_DISTANCE", 0.0),
+            default_duplicate_session_policy: config::env_or(
+                "DUPLICATE_SESSION_POLICY",
+                DuplicateSessionPolicy::Allow,
+            ),
+            receiver_report_interval: Duration::from_millis(config::env_or(
+                "RECEIVER_REPORT_INTERVAL_MILLIS",
+                1000,
+            )),
+        }
+    }
+}
+
+/// A handle to the task owning the PeerManager of a room.
+///
+/// The tasks given to the handle run on the PeerManager one at a time in the order given,
+/// so the peers of a room never wait for those of other rooms.
+/// The task of the room ends when every handle to it has been dropped.
+///
+#[derive(Clone)]
+pub struct RoomRef {
+    room_id: i64,
+    tx: mpsc::UnboundedSender<RoomTask>,
+}
+
+impl RoomRef {
+    fn spawn(room_id: i64, config: RoomConfig) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<RoomTask>();
+        tokio::spawn(async move {
+            info!("The room {} opens.", room_id);
+            let mut peer_manager = PeerManager::new(room_id, config);
+            while let Some(task) = rx.recv().await {
+                task(&mut peer_manager);
+            }
+            info!("The room {} closes.", room_id);
+        });
+        RoomRef { room_id, tx }
+    }
+
+    /// Runs the task on the PeerManager of the room without waiting for it.
+    ///
+    pub fn tell<F>(&self, task: F)
+    where
+        F: FnOnce(&mut PeerManager) + Send + 'static,
+    {
+        if self.tx.send(Box::new(task)).is_err() {
+            warn!("The room {} has already closed.", self.room_id);
+        }
+    }
+
+    /// Runs the task on the PeerManager of the room and waits for its result.
+    ///
+    /// Returns None if the room has already closed.
+    ///
+    pub async fn ask<F, R>(&self, task: F) -> Option<R>
+    where
+        F: FnOnce(&mut PeerManager) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx_result, rx_result) = oneshot::channel();
+        self.tell(move |peer_manager| {
+            // The asker has gone if it has been cancelled.
+            let _ = tx_result.send(task(peer_manager));
+        });
+        rx_result.await.ok()
+    }
+}
+
+/// The rooms the peers are in.
+///
+/// A room opens when the first session joins it and is forgotten when the last one leaves,
+/// so the registry is locked only while sessions join and leave.
+///
+pub struct RoomRegistry {
+    rooms: Mutex<HashMap<i64, (RoomRef, usize)>>,
+    config: RoomConfig,
+}
+
+pub type RoomRegistryRef = Arc<RoomRegistry>;
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        RoomRegistry {
+            rooms: Mutex::new(HashMap::new()),
+            config: RoomConfig::from_env(),
+        }
+    }
+
+    /// Joins the room, opening it if no session is in it.
+    ///
+    pub fn join(self: &Arc<Self>, room_id: i64) -> RoomMembership {
+        let mut rooms = self.rooms.lock().unwrap();
+        let (room, sessions) = rooms
+            .entry(room_id)
+            .or_insert_with(|| (RoomRef::spawn(room_id, self.config), 0));
+        *sessions += 1;
+
+        RoomMembership {
+            room: room.clone(),
+            registry: Arc::clone(self),
+        }
+    }
+
+    /// Returns the room if any session is in it.
+    ///
+    pub fn get(&self, room_id: i64) -> Option<RoomRef> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(&room_id).map(|(room, _)| room.clone())
+    }
+
+    fn leave(&self, room_id: i64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some((_, sessions)) = rooms.get_mut(&room_id) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                rooms.remove(&room_id);
+            }
+        }
+    }
+}
+
+/// A session's place in a room, which leaves the room when dropped.
+///
+pub struct RoomMembership {
+    room: RoomRef,
+    registry: RoomRegistryRef,
+}
+
+impl RoomMembership {
+    pub fn room(&self) -> &RoomRef {
+        &self.room
+    }
+}
+
+impl Drop for RoomMembership {
+    fn drop(&mut self) {
+        self.registry.leave(self.room.room_id);
+    }
+}
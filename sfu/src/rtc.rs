@@ -0,0 +1,289 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use webrtc::api::interceptor_registry::{configure_rtcp_reports, configure_twcc_receiver_only};
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::interceptor::nack::{generator::Generator, responder::Responder};
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+// use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpHeaderExtensionCapability, RTPCodecType};
+
+use log::{info, warn};
+
+use crate::codec::CodecPolicy;
+use crate::config;
+use crate::ice;
+use crate::speaker;
+
+/// How the public IPs in 'NAT_1TO1_IPS' are advertised.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Nat1To1CandidateType {
+    /// The host candidates carry the public IPs instead of the local ones.
+    Host,
+    /// The public IPs are added as server reflexive candidates.
+    Srflx,
+}
+
+impl FromStr for Nat1To1CandidateType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Nat1To1CandidateType::Host),
+            "srflx" => Ok(Nat1To1CandidateType::Srflx),
+            _ => Err(format!("Unknown candidate type {:?}.", s)),
+        }
+    }
+}
+
+/// Whether the local IPs are hidden behind mDNS names.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MdnsMode {
+    Disabled,
+    /// The mDNS names of remote candidates are resolved.
+    QueryOnly,
+    /// The local host candidates are also gathered with mDNS names.
+    QueryAndGather,
+}
+
+impl FromStr for MdnsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(MdnsMode::Disabled),
+            "query_only" => Ok(MdnsMode::QueryOnly),
+            "query_and_gather" => Ok(MdnsMode::QueryAndGather),
+            _ => Err(format!("Unknown mDNS mode {:?}.", s)),
+        }
+    }
+}
+
+/// The ICE networking of the peer connections.
+///
+#[derive(Debug, Clone)]
+pub struct IceNetworkConfig {
+    /// The range of the UDP ports for the host candidates. 0 lets the OS pick each port.
+    pub udp_port_min: u16,
+    pub udp_port_max: u16,
+    /// The single UDP port shared by every peer connection. 0 disables the mux.
+    pub udp_mux_port: u16,
+    /// The public IPs of a host behind a 1:1 NAT, e.g. a container or a cloud instance.
+    pub nat_1to1_ips: Vec<String>,
+    pub nat_1to1_candidate_type: Nat1To1CandidateType,
+    /// The names of the network interfaces candidates are gathered on. Empty allows any.
+    pub interfaces: Vec<String>,
+    pub ipv6_enabled: bool,
+    pub mdns_mode: MdnsMode,
+}
+
+impl IceNetworkConfig {
+    pub fn from_env() -> Self {
+        IceNetworkConfig {
+            udp_port_min: config::env_or("ICE_UDP_PORT_MIN", 0),
+            udp_port_max: config::env_or("ICE_UDP_PORT_MAX", 0),
+            udp_mux_port: config::env_or("ICE_UDP_MUX_PORT", 0),
+            nat_1to1_ips: list_from_env("NAT_1TO1_IPS"),
+            nat_1to1_candidate_type: config::env_or(
+                "NAT_1TO1_CANDIDATE_TYPE",
+                Nat1To1CandidateType::Host,
+            ),
+            interfaces: list_from_env("ICE_INTERFACES"),
+            ipv6_enabled: config::env_or("ICE_IPV6_ENABLED", false),
+            mdns_mode: config::env_or("ICE_MDNS_MODE", MdnsMode::Disabled),
+        }
+    }
+
+    /// Builds the SettingEngine applying the settings.
+    ///
+    /// Returns an error for settings the WebRTC stack can't honor, so that the SFU doesn't
+    /// start with candidates that peers behind strict firewalls can't reach.
+    ///
+    pub fn setting_engine(&self) -> Result<SettingEngine, String> {
+        let mut setting_engine = SettingEngine::default();
+
+        let udp_port_range = self.udp_port_min != 0 || self.udp_port_max != 0;
+        if self.udp_mux_port != 0 {
+            if udp_port_range {
+                return Err(
+                    "ICE_UDP_MUX_PORT can't be combined with ICE_UDP_PORT_MIN and ICE_UDP_PORT_MAX."
+                        .to_owned(),
+                );
+            }
+            // The mux gathers a host candidate on the port and no server reflexive one.
+            if !self.nat_1to1_ips.is_empty()
+                && self.nat_1to1_candidate_type == Nat1To1CandidateType::Srflx
+            {
+                return Err(
+                    "NAT_1TO1_CANDIDATE_TYPE=srflx can't be combined with ICE_UDP_MUX_PORT. \
+                    Use NAT_1TO1_CANDIDATE_TYPE=host instead."
+                        .to_owned(),
+                );
+            }
+            setting_engine.set_udp_network(UDPNetwork::Muxed(self.udp_mux()?));
+            info!(
+                "Gather the host candidates of every peer on the UDP port {}.",
+                self.udp_mux_port
+            );
+        } else if udp_port_range {
+            let ephemeral_udp = EphemeralUDP::new(self.udp_port_min, self.udp_port_max)
+                .map_err(|e| format!("Invalid UDP port range: {:?}", e))?;
+            setting_engine.set_udp_network(UDPNetwork::Ephemeral(ephemeral_udp));
+            info!(
+                "Gather the host candidates on the UDP ports {}-{}.",
+                self.udp_port_min, self.udp_port_max
+            );
+        }
+
+        // webrtc 0.4 gathers no ICE-TCP candidates, so peers behind firewalls blocking UDP
+        // have to relay TCP through TURN.
+        let mut network_types = vec![NetworkType::Udp4];
+        if self.ipv6_enabled {
+            network_types.push(NetworkType::Udp6);
+        }
+        setting_engine.set_network_types(network_types);
+
+        if !self.nat_1to1_ips.is_empty() {
+            let candidate_type = match self.nat_1to1_candidate_type {
+                Nat1To1CandidateType::Host => RTCIceCandidateType::Host,
+                Nat1To1CandidateType::Srflx => RTCIceCandidateType::Srflx,
+            };
+            setting_engine.set_nat_1to1_ips(self.nat_1to1_ips.clone(), candidate_type);
+        }
+
+        if !self.interfaces.is_empty() {
+            let interfaces = self.interfaces.clone();
+            setting_engine.set_interface_filter(Box::new(move |name: &str| {
+                interfaces.iter().any(|interface| interface == name)
+            }));
+        }
+
+        setting_engine.set_ice_multicast_dns_mode(match self.mdns_mode {
+            MdnsMode::Disabled => MulticastDnsMode::Disabled,
+            MdnsMode::QueryOnly => MulticastDnsMode::QueryOnly,
+            MdnsMode::QueryAndGather => MulticastDnsMode::QueryAndGather,
+        });
+
+        Ok(setting_engine)
+    }
+
+    /// Binds the socket shared by every peer connection, whose packets are told apart
+    /// by the ICE username fragments.
+    ///
+    fn udp_mux(&self) -> Result<Arc<UDPMuxDefault>, String> {
+        // The IPv6 socket is dual-stack, and the mux maps IPv4 peers to IPv6 addresses.
+        let ip: IpAddr = if self.ipv6_enabled {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        };
+        let socket = std::net::UdpSocket::bind((ip, self.udp_mux_port))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .and_then(tokio::net::UdpSocket::from_std)
+            .map_err(|e| format!("Can't bind the UDP port {}: {}", self.udp_mux_port, e))?;
+
+        Ok(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+    }
+}
+
+fn list_from_env(key: &str) -> Vec<String> {
+    let list: String = config::env_or(key, "".to_owned());
+    list.split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Registers the interceptors of webrtc-rs's defaults with the NACK responder sized by
+/// 'NACK_BUFFER_SIZE', the number of packets kept for each stream sent to a subscriber.
+///
+/// The NACK feedback is declared by the codecs themselves.
+///
+fn register_interceptors(m: &mut MediaEngine) -> Result<Registry, webrtc::Error> {
+    // The responder takes the size as a power of 2 up to 32768.
+    const MAX_NACK_BUFFER_SIZE: u16 = 1 << 15;
+    let nack_buffer_size: u16 = config::env_or("NACK_BUFFER_SIZE", 1024);
+    if nack_buffer_size > MAX_NACK_BUFFER_SIZE {
+        warn!(
+            "NACK_BUFFER_SIZE {} is larger than {} so use {}.",
+            nack_buffer_size, MAX_NACK_BUFFER_SIZE, MAX_NACK_BUFFER_SIZE
+        );
+    }
+    let log2_size = nack_buffer_size
+        .clamp(1, MAX_NACK_BUFFER_SIZE)
+        .next_power_of_two()
+        .trailing_zeros() as u8;
+    info!("Keep {} packets of each stream to answer NACKs.", 1u32 << log2_size);
+
+    let mut registry = Registry::new();
+    registry.add(Box::new(Responder::builder().with_log2_size(log2_size)));
+    registry.add(Box::new(Generator::builder()));
+    registry = configure_rtcp_reports(registry);
+    configure_twcc_receiver_only(registry, m)
+}
+
+/// The WebRTC API shared by the peer connections of every peer.
+///
+/// The codecs, interceptors and ICE settings are configured once at startup.
+/// Each peer connection still negotiates on its own copy of the MediaEngine,
+/// and its transceivers are restricted to the codecs of its room.
+///
+pub struct RtcApi {
+    api: API,
+    codec_policy: CodecPolicy,
+}
+
+pub type RtcApiRef = Arc<RtcApi>;
+
+impl RtcApi {
+    pub fn new(ice_network: &IceNetworkConfig, codec_policy: CodecPolicy) -> Result<Self, String> {
+        let mut m = MediaEngine::default();
+        codec_policy.register(&mut m).map_err(|e| e.to_string())?;
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: speaker::AUDIO_LEVEL_URI.to_owned(),
+            },
+            RTPCodecType::Audio,
+            vec![],
+        )
+        .map_err(|e| e.to_string())?;
+        let registry = register_interceptors(&mut m).map_err(|e| e.to_string())?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(ice_network.setting_engine()?)
+            .build();
+
+        Ok(RtcApi { api, codec_policy })
+    }
+
+    /// The codecs allowed on the server, which rooms may narrow down.
+    ///
+    pub fn codec_policy(&self) -> &CodecPolicy {
+        &self.codec_policy
+    }
+
+    pub async fn new_peer_connection(&self) -> Result<RTCPeerConnection, webrtc::Error> {
+        let ice_servers = ice::create_ice_server_config("sfu");
+        let config = RTCConfiguration {
+            ice_servers: ice_servers,
+            // ice_transport_policy: RTCIceTransportPolicy::Relay,
+            ..Default::default()
+        };
+
+        self.api.new_peer_connection(config).await
+    }
+}
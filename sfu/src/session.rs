@@ -0,0 +1,268 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::SplitStream;
+use futures::{SinkExt, StreamExt};
+use log::{error, warn};
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+
+use crate::config;
+use crate::protocol::ClientMessage;
+
+/// The WebSocket close code and reason told to a peer whose session can't be resumed.
+pub const RESUME_FAILED: (u16, &str) = (4004, "resume_failed");
+
+/// The WebSocket close code and reason told to a peer rejected by 'DuplicateSessionPolicy::Reject'.
+pub const DUPLICATE_SESSION: (u16, &str) = (4006, "duplicate_session");
+
+/// The WebSocket close code and reason told to a peer that hasn't given valid credentials in time.
+pub const UNAUTHENTICATED: (u16, &str) = (4008, "unauthenticated");
+
+/// The WebSocket close code and reason told to a peer that can't join while the database is down.
+pub const TRY_AGAIN_LATER: (u16, &str) = (1013, "try_again_later");
+
+/// The WebSocket close code and reason told to a peer that can't join for an unexpected error.
+pub const INTERNAL_ERROR: (u16, &str) = (1011, "internal_error");
+
+/// The subprotocol the SFU selects for a peer passing its credentials in 'Sec-WebSocket-Protocol'.
+pub const SIGNALING_SUBPROTOCOL: &str = "waku-signaling";
+
+// The values of 'Sec-WebSocket-Protocol' carrying the credentials are prefixed with these.
+// The member token is base64url without padding since '=' isn't allowed there.
+const TOKEN_SUBPROTOCOL_PREFIX: &str = "token.";
+const RESUME_TOKEN_SUBPROTOCOL_PREFIX: &str = "resume.";
+
+/// What to do when a member who already has a session in a room joins it again,
+/// e.g. from another tab or device.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DuplicateSessionPolicy {
+    /// Both sessions are kept.
+    Allow,
+    /// The existing sessions are taken over by the new one.
+    Replace,
+    /// The new session is rejected.
+    Reject,
+}
+
+impl FromStr for DuplicateSessionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(DuplicateSessionPolicy::Allow),
+            "replace" => Ok(DuplicateSessionPolicy::Replace),
+            "reject" => Ok(DuplicateSessionPolicy::Reject),
+            _ => Err(format!("Unknown duplicate session policy {:?}.", s)),
+        }
+    }
+}
+
+/// Settings of the liveness checks on signaling sessions.
+///
+#[derive(Debug, Copy, Clone)]
+pub struct LivenessConfig {
+    /// The interval at which the SFU pings each WebSocket connection.
+    pub ping_interval: Duration,
+    /// A peer without any activity for this period is regarded as stale.
+    pub stale_after: Duration,
+    /// A peer without any activity for this period is evicted.
+    pub idle_timeout: Duration,
+    /// A peer whose WebSocket has been lost can resume its session within this period.
+    /// Zero disables resumption.
+    pub resume_grace_period: Duration,
+}
+
+impl LivenessConfig {
+    pub fn from_env() -> Self {
+        let ping_interval = Duration::from_secs(config::env_or("PING_INTERVAL_SECS", 10));
+        LivenessConfig {
+            ping_interval,
+            stale_after: ping_interval * 2,
+            idle_timeout: Duration::from_secs(config::env_or("IDLE_TIMEOUT_SECS", 30)),
+            resume_grace_period: Duration::from_secs(config::env_or("RESUME_GRACE_SECS", 20)),
+        }
+    }
+}
+
+/// The credentials with which a peer opens or resumes a session.
+///
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub token: Option<String>,
+    pub resume_token: Option<String>,
+    /// Whether they have been given in the URL path, which is accepted only until
+    /// every client has migrated.
+    pub in_path: bool,
+}
+
+impl Credentials {
+    pub fn from_path(token: String, resume_token: Option<String>) -> Self {
+        Credentials {
+            token: Some(token),
+            resume_token,
+            in_path: true,
+        }
+    }
+
+    /// Reads the credentials from the comma separated values of 'Sec-WebSocket-Protocol'.
+    ///
+    pub fn from_subprotocols(subprotocols: Option<&str>) -> Self {
+        let mut credentials = Credentials::default();
+        for value in subprotocols.unwrap_or("").split(',').map(str::trim) {
+            if let Some(token) = value.strip_prefix(TOKEN_SUBPROTOCOL_PREFIX) {
+                credentials.token = Some(token.to_owned());
+            } else if let Some(resume_token) = value.strip_prefix(RESUME_TOKEN_SUBPROTOCOL_PREFIX) {
+                credentials.resume_token = Some(resume_token.to_owned());
+            }
+        }
+        credentials
+    }
+
+    /// Takes the credentials given on the upgrade request,
+    /// or else waits for the peer to send them in an 'authenticate' message.
+    ///
+    /// Returns None, after closing the WebSocket, if the peer doesn't authenticate itself in time
+    /// or gives them in the URL path while it is no longer accepted.
+    ///
+    pub async fn authenticate(self, ws: &mut WebSocket) -> Option<Credentials> {
+        let credentials = if self.in_path && !config::env_or("TOKEN_IN_PATH_ENABLED", true) {
+            warn!("Reject the peer giving its token in the URL path.");
+            None
+        } else if self.token.is_some() {
+            Some(self)
+        } else {
+            let timeout = Duration::from_secs(config::env_or("AUTH_TIMEOUT_SECS", 5));
+            let msg = match tokio::time::timeout(timeout, ws.next()).await {
+                Ok(Some(Ok(msg))) => msg.to_str().map(serde_json::from_str::<ClientMessage>),
+                Ok(_) => return None,
+                Err(_) => {
+                    warn!("The peer hasn't authenticated itself within {:?}.", timeout);
+                    Err(())
+                }
+            };
+            match msg {
+                Ok(Ok(ClientMessage::Authenticate(auth))) => Some(Credentials {
+                    token: Some(auth.token),
+                    resume_token: auth.resume_token,
+                    in_path: false,
+                }),
+                Ok(_) => {
+                    warn!("The first message of the peer is not 'authenticate'.");
+                    None
+                }
+                Err(_) => None,
+            }
+        };
+
+        if credentials.is_none() {
+            let (code, reason) = UNAUTHENTICATED;
+            if let Err(e) = ws.send(Message::close_with(code, reason)).await {
+                error!("{:?} while rejecting the peer.", e);
+            }
+        }
+        credentials
+    }
+}
+
+/// Tracks the last activity of a peer on both the WebSocket and the data channel.
+///
+pub struct ActivityMonitor {
+    last_active: Mutex<Instant>,
+    stale: AtomicBool,
+}
+
+impl ActivityMonitor {
+    pub fn new() -> Self {
+        ActivityMonitor {
+            last_active: Mutex::new(Instant::now()),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    /// Records an activity and returns whether the peer had been stale until now.
+    ///
+    pub fn touch(&self) -> bool {
+        *self.last_active.lock().unwrap() = Instant::now();
+        self.stale.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    /// Marks the peer as stale and returns whether it has just become stale.
+    ///
+    pub fn mark_stale(&self) -> bool {
+        !self.stale.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// A handle through which a WebSocket reconnected with a resume token
+/// is attached to the existing session of a peer.
+///
+pub struct ResumeHandle {
+    token: String,
+    member_id: i64,
+    grace_period: Duration,
+    tx_ws: UnboundedSender<WebSocket>,
+}
+
+impl ResumeHandle {
+    pub fn new(member_id: i64, grace_period: Duration, tx_ws: UnboundedSender<WebSocket>) -> Self {
+        ResumeHandle {
+            token: Uuid::new_v4().to_simple().to_string(),
+            member_id,
+            grace_period,
+            tx_ws,
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Hands the WebSocket to the session if the token has been issued to the member.
+    ///
+    /// The WebSocket is given back when the session can't be resumed.
+    ///
+    pub fn attach(
+        &self,
+        token: &str,
+        member_id: i64,
+        ws: Box<WebSocket>,
+    ) -> Result<(), Box<WebSocket>> {
+        if self.token != token || self.member_id != member_id {
+            return Err(ws);
+        }
+        self.tx_ws.send(*ws).map_err(|e| Box::new(e.0))
+    }
+}
+
+/// Reads the next message of a session, which never comes while its WebSocket is lost.
+///
+pub async fn next_message(
+    rx_ws: &mut Option<SplitStream<WebSocket>>,
+) -> Option<Result<Message, warp::Error>> {
+    match rx_ws {
+        Some(rx_ws) => rx_ws.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits until the deadline if any, otherwise forever.
+///
+pub async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// The URI of the RTP header extension carrying audio levels (RFC 6464).
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+
+/// The interval at which each audio track reports its averaged level.
+pub const AUDIO_LEVEL_REPORT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The level (-dBov) the extension uses for digital silence.
+const SILENT_LEVEL: u8 = 127;
+
+/// Weight of the newest report in the exponential moving average.
+const SMOOTHING_FACTOR: f64 = 0.3;
+
+/// Loudness below which a peer is never considered as speaking.
+const SPEAKING_THRESHOLD: f64 = 40.0;
+
+/// How much louder a candidate must be than the current speaker to take over.
+const SWITCH_MARGIN: f64 = 5.0;
+
+/// The minimum time the dominant speaker is kept before switching to another one.
+const MIN_SWITCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Levels not reported within this period are regarded as silence.
+const LEVEL_EXPIRATION: Duration = Duration::from_millis(1000);
+
+/// Converts a level of the 'ssrc-audio-level' extension payload into loudness.
+///
+/// The payload is 1 byte: the 'V' (voice activity) bit followed by the level
+/// expressed in -dBov (0 is the loudest and 127 is silence).
+///
+pub fn parse_audio_level(payload: &[u8]) -> Option<u8> {
+    payload.first().map(|b| b & 0x7f)
+}
+
+struct SpeakerLevel {
+    smoothed: f64,
+    updated_at: Instant,
+}
+
+/// Detects the dominant speaker of a room from the audio levels of its publishers.
+///
+pub struct ActiveSpeakerDetector {
+    levels: HashMap<Uuid, SpeakerLevel>,
+    dominant: Option<Uuid>,
+    switched_at: Option<Instant>,
+}
+
+impl ActiveSpeakerDetector {
+    pub fn new() -> Self {
+        ActiveSpeakerDetector {
+            levels: HashMap::new(),
+            dominant: None,
+            switched_at: None,
+        }
+    }
+
+    /// Records an averaged level reported by a publisher
+    /// and returns the new dominant speaker if it has changed.
+    ///
+    pub fn update(&mut self, peer_id: &Uuid, level: u8, now: Instant) -> Option<Uuid> {
+        let loudness = (SILENT_LEVEL - level.min(SILENT_LEVEL)) as f64;
+        let speaker_level = self.levels.entry(peer_id.clone()).or_insert(SpeakerLevel {
+            smoothed: 0.0,
+            updated_at: now,
+        });
+        speaker_level.smoothed =
+            SMOOTHING_FACTOR * loudness + (1.0 - SMOOTHING_FACTOR) * speaker_level.smoothed;
+        speaker_level.updated_at = now;
+
+        self.detect(now)
+    }
+
+    /// Forgets the peer and returns whether it was the dominant speaker.
+    ///
+    pub fn remove(&mut self, peer_id: &Uuid) -> bool {
+        self.levels.remove(peer_id);
+        if self.dominant.as_ref() != Some(peer_id) {
+            return false;
+        }
+        self.dominant = None;
+        self.switched_at = None;
+        true
+    }
+
+    pub fn dominant(&self) -> Option<Uuid> {
+        self.dominant
+    }
+
+    fn loudness_of(&self, peer_id: &Uuid, now: Instant) -> f64 {
+        self.levels
+            .get(peer_id)
+            .filter(|l| now.duration_since(l.updated_at) < LEVEL_EXPIRATION)
+            .map(|l| l.smoothed)
+            .unwrap_or(0.0)
+    }
+
+    fn detect(&mut self, now: Instant) -> Option<Uuid> {
+        if let Some(switched_at) = self.switched_at {
+            if now.duration_since(switched_at) < MIN_SWITCH_INTERVAL {
+                return None;
+            }
+        }
+
+        let (candidate, candidate_loudness) = self
+            .levels
+            .keys()
+            .map(|id| (id.clone(), self.loudness_of(id, now)))
+            .fold(None, |max: Option<(Uuid, f64)>, (id, l)| match max {
+                Some((_, max_l)) if max_l >= l => max,
+                _ => Some((id, l)),
+            })?;
+
+        if candidate_loudness < SPEAKING_THRESHOLD {
+            return None;
+        }
+
+        if let Some(dominant) = self.dominant {
+            if dominant == candidate
+                || candidate_loudness < self.loudness_of(&dominant, now) + SWITCH_MARGIN
+            {
+                return None;
+            }
+        }
+
+        self.dominant = Some(candidate);
+        self.switched_at = Some(now);
+        Some(candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOUD: u8 = 0;
+    const QUIET: u8 = 120;
+    const REPORT: Duration = AUDIO_LEVEL_REPORT_INTERVAL;
+
+    #[test]
+    fn parse_audio_level_ignores_voice_activity_bit() {
+        assert_eq!(parse_audio_level(&[0x80 | 30]), Some(30));
+        assert_eq!(parse_audio_level(&[30]), Some(30));
+        assert_eq!(parse_audio_level(&[]), None);
+    }
+
+    #[test]
+    fn quiet_peer_never_becomes_dominant() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let peer_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        for i in 0..20 {
+            assert_eq!(detector.update(&peer_id, QUIET, start + REPORT * i), None);
+        }
+        assert_eq!(detector.dominant(), None);
+    }
+
+    #[test]
+    fn loud_peer_becomes_dominant_once_smoothed_over_threshold() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let peer_id = Uuid::new_v4();
+        let start = Instant::now();
+
+        // A single loud report is damped below the threshold.
+        assert_eq!(detector.update(&peer_id, LOUD, start), None);
+        assert_eq!(
+            detector.update(&peer_id, LOUD, start + REPORT),
+            Some(peer_id)
+        );
+        assert_eq!(detector.dominant(), Some(peer_id));
+
+        // It isn't reported again while it stays dominant.
+        assert_eq!(detector.update(&peer_id, LOUD, start + REPORT * 10), None);
+    }
+
+    #[test]
+    fn switching_waits_for_min_interval() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let start = Instant::now();
+
+        detector.update(&first, LOUD, start);
+        let switched_at = start + REPORT;
+        assert_eq!(detector.update(&first, LOUD, switched_at), Some(first));
+
+        // The second peer gets louder than the first one, but too soon after the last switch.
+        let mut now = switched_at;
+        while now + REPORT < switched_at + MIN_SWITCH_INTERVAL {
+            now += REPORT;
+            assert_eq!(detector.update(&second, LOUD, now), None);
+            assert_eq!(detector.update(&first, QUIET, now), None);
+        }
+
+        now = switched_at + MIN_SWITCH_INTERVAL;
+        assert_eq!(detector.update(&second, LOUD, now), Some(second));
+        assert_eq!(detector.dominant(), Some(second));
+    }
+
+    #[test]
+    fn switching_requires_margin() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let start = Instant::now();
+
+        detector.update(&first, 10, start);
+        let mut now = start;
+        for _ in 0..50 {
+            now += REPORT;
+            detector.update(&first, 10, now);
+            detector.update(&second, 6, now);
+        }
+        // Slightly louder than the dominant speaker isn't enough to take over.
+        assert_eq!(detector.dominant(), Some(first));
+
+        let mut switched = None;
+        for _ in 0..10 {
+            now += REPORT;
+            switched = switched
+                .or(detector.update(&first, 20, now))
+                .or(detector.update(&second, 6, now));
+        }
+        assert_eq!(switched, Some(second));
+    }
+
+    #[test]
+    fn expired_levels_count_as_silence() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let start = Instant::now();
+
+        detector.update(&first, LOUD, start);
+        assert_eq!(detector.update(&first, LOUD, start + REPORT), Some(first));
+
+        // The second peer is as loud as the first one was, which takes over
+        // only because the first one has stopped reporting.
+        let now = start + REPORT + LEVEL_EXPIRATION;
+        detector.update(&second, LOUD, now - REPORT);
+        assert_eq!(detector.update(&second, LOUD, now), Some(second));
+    }
+
+    #[test]
+    fn removing_dominant_speaker_clears_it() {
+        let mut detector = ActiveSpeakerDetector::new();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let start = Instant::now();
+
+        detector.update(&first, LOUD, start);
+        detector.update(&first, LOUD, start + REPORT);
+        assert!(!detector.remove(&second));
+        assert_eq!(detector.dominant(), Some(first));
+
+        assert!(detector.remove(&first));
+        assert_eq!(detector.dominant(), None);
+
+        // Another speaker may take over at once.
+        detector.update(&second, LOUD, start + REPORT);
+        assert_eq!(
+            detector.update(&second, LOUD, start + REPORT * 2),
+            Some(second)
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use std::any::Any;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalContext, TrackLocalWriter};
+use webrtc::Error;
+
+use log::{debug, error, warn};
+
+use crate::codec::CodecPolicy;
+use crate::keyframe::KeyframeCache;
+
+/// The number of packets of a publisher's track buffered for the slowest virtual track.
+pub const PACKET_FEED_CAPACITY: usize = 256;
+
+/// How many times the first packet of a replayed keyframe is written until a sender takes it.
+const REPLAY_ATTEMPTS: usize = 50;
+
+const REPLAY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The packets of a publisher's track, fanned out to the virtual tracks it occupies,
+/// and the latest keyframe of its video with which a subscriber starts receiving it.
+///
+#[derive(Clone)]
+pub struct PacketFeed {
+    packets: broadcast::Sender<Packet>,
+    keyframe_cache: Option<Arc<Mutex<KeyframeCache>>>,
+}
+
+impl PacketFeed {
+    pub fn new(keyframe_cache: Option<KeyframeCache>) -> Self {
+        let (packets, _) = broadcast::channel(PACKET_FEED_CAPACITY);
+        PacketFeed {
+            packets,
+            keyframe_cache: keyframe_cache.map(|cache| Arc::new(Mutex::new(cache))),
+        }
+    }
+
+    pub fn send(&self, packet: &Packet) {
+        if let Some(keyframe_cache) = &self.keyframe_cache {
+            keyframe_cache.lock().unwrap().push(packet);
+        }
+        if self.packets.receiver_count() > 0 {
+            let _ = self.packets.send(packet.clone());
+        }
+    }
+
+    /// Whether a subscriber starting now would receive a keyframe at once.
+    ///
+    pub fn has_fresh_keyframe(&self) -> bool {
+        self.keyframe_cache
+            .as_ref()
+            .map(|keyframe_cache| keyframe_cache.lock().unwrap().fresh().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Starts receiving the packets, led by the latest keyframe unless it is stale.
+    ///
+    pub fn subscribe(&self) -> FeedSubscription {
+        // Subscribed before the keyframe is taken so that no packet in between is missed.
+        let packets = self.packets.subscribe();
+        let keyframe = self
+            .keyframe_cache
+            .as_ref()
+            .and_then(|keyframe_cache| keyframe_cache.lock().unwrap().fresh());
+        FeedSubscription {
+            packets,
+            keyframe,
+            replayed: 0,
+            replayed_up_to: None,
+        }
+    }
+}
+
+pub struct FeedSubscription {
+    packets: broadcast::Receiver<Packet>,
+    /// The packets of the latest keyframe, which some of the packets received may repeat.
+    keyframe: Option<Arc<Vec<Packet>>>,
+    /// The number of the keyframe's packets already taken.
+    replayed: usize,
+    /// The original sequence number of the last packet replayed from the keyframe.
+    replayed_up_to: Option<u16>,
+}
+
+impl FeedSubscription {
+    pub fn has_keyframe(&self) -> bool {
+        self.keyframe.is_some()
+    }
+
+    /// The next packet to forward, or None once the publisher's track has ended.
+    ///
+    /// The packets of the keyframe come first, followed by the live packets after them
+    /// so that their sequence numbers keep going forward.
+    ///
+    pub async fn next(&mut self) -> Option<Packet> {
+        if let Some(packet) = self
+            .keyframe
+            .as_ref()
+            .and_then(|keyframe| keyframe.get(self.replayed))
+        {
+            self.replayed += 1;
+            self.replayed_up_to = Some(packet.header.sequence_number);
+            return Some(packet.clone());
+        }
+
+        loop {
+            let packet = match self.packets.recv().await {
+                Ok(packet) => packet,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("{} packets are dropped for a slow subscriber.", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            if let Some(replayed) = self.replayed_up_to {
+                if (packet.header.sequence_number.wrapping_sub(replayed) as i16) <= 0 {
+                    continue;
+                }
+                self.replayed_up_to = None;
+            }
+            return Some(packet);
+        }
+    }
+}
+
+/// How the publishers' tracks are forwarded to a subscriber.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ForwardingMode {
+    /// Each publisher's track is forwarded through a sender slot of its own,
+    /// and a slot is added with an offer when none of the kind is free.
+    Tracks,
+    /// A subscriber has a fixed number of virtual tracks negotiated once,
+    /// and the publishers occupying them are switched without renegotiation.
+    Virtual,
+}
+
+impl FromStr for ForwardingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tracks" => Ok(ForwardingMode::Tracks),
+            "virtual" => Ok(ForwardingMode::Virtual),
+            _ => Err(format!("Unknown forwarding mode {:?}.", s)),
+        }
+    }
+}
+
+/// The codec of the virtual tracks of the kind, which is the first one of the room's policy.
+///
+/// Publishers' tracks in other codecs can't occupy them.
+///
+pub fn codec_of(codec_policy: &CodecPolicy, kind: RTPCodecType) -> Option<RTCRtpCodecCapability> {
+    codec_policy
+        .parameters(kind)
+        .into_iter()
+        .next()
+        .map(|parameters| parameters.capability)
+}
+
+/// A track of a subscriber that forwards the packets of whichever publisher's track occupies it.
+///
+/// The sequence numbers and timestamps are rewritten so that they run on across switches,
+/// and the SSRC and payload type are replaced with the ones negotiated for the track.
+/// The subscriber therefore sees one continuous stream per track.
+///
+pub struct VirtualTrack {
+    track: Arc<TrackLocalStaticRTP>,
+    rewriter: Arc<Mutex<RtpRewriter>>,
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl VirtualTrack {
+    pub fn new(peer_id: &Uuid, kind: RTPCodecType, codec: RTCRtpCodecCapability) -> Self {
+        let clock_rate = codec.clock_rate;
+        VirtualTrack {
+            track: Arc::new(TrackLocalStaticRTP::new(
+                codec,
+                format!("sfu-virtual-{:?}-{:?}", kind, Uuid::new_v4()),
+                format!("sfu-virtual-stream-{:?}", peer_id),
+            )),
+            rewriter: Arc::new(Mutex::new(RtpRewriter::new(clock_rate))),
+            forwarder: Mutex::new(None),
+        }
+    }
+
+    pub fn track(&self) -> Arc<TrackLocalStaticRTP> {
+        Arc::clone(&self.track)
+    }
+
+    /// Starts forwarding the packets from the feed in place of the current source.
+    /// With no feed, the track just stops sending.
+    ///
+    /// The cached keyframe is replayed first so that the subscriber renders the video without
+    /// waiting for the publisher's next keyframe. The live packets keep their distance from it,
+    /// so the subscriber sees the packets sent in between as lost instead of decoding frames
+    /// that refer to them, until it asks the publisher for a new keyframe itself.
+    /// Without a cached keyframe, the publisher is asked for one when the track is switched.
+    ///
+    pub fn switch(&self, peer_id: &Uuid, feed: Option<FeedSubscription>) {
+        let mut forwarder = self.forwarder.lock().unwrap();
+        if let Some(forwarder) = forwarder.take() {
+            forwarder.abort();
+        }
+        self.rewriter.lock().unwrap().restart();
+
+        let mut feed = match feed {
+            Some(feed) => feed,
+            None => return,
+        };
+        let peer_id = peer_id.clone();
+        let track = Arc::clone(&self.track);
+        let rewriter = Arc::clone(&self.rewriter);
+        *forwarder = Some(tokio::spawn(async move {
+            while let Some(mut packet) = feed.next().await {
+                rewriter.lock().unwrap().rewrite(&mut packet.header);
+
+                if let Err(e) = track.write_rtp(&packet).await {
+                    if Error::ErrClosedPipe != e {
+                        error!("virtual track write_rtp got error: {} on {:?}.", e, peer_id);
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+}
+
+impl Drop for VirtualTrack {
+    fn drop(&mut self) {
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            forwarder.abort();
+        }
+    }
+}
+
+/// A publisher's track attached to the sender of a subscriber in 'ForwardingMode::Tracks',
+/// which starts with the latest keyframe once the sender is bound.
+///
+/// A binding with a keyframe to replay gets a track of its own fed with the publisher's packets,
+/// so that the live packets follow the keyframe in the order of their sequence numbers.
+/// The others are bound to the publisher's track shared by the subscribers.
+///
+pub struct ReplayingTrack {
+    shared: Arc<TrackLocalStaticRTP>,
+    track: Arc<TrackLocalStaticRTP>,
+    feed: PacketFeed,
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ReplayingTrack {
+    /// Wraps the track to attach it to a sender, unless it has no feed to replay keyframes from.
+    ///
+    pub fn attach(
+        track: &Arc<TrackLocalStaticRTP>,
+        feed: Option<&PacketFeed>,
+    ) -> Arc<dyn TrackLocal + Send + Sync> {
+        match feed {
+            Some(feed) => Arc::new(ReplayingTrack {
+                shared: Arc::clone(track),
+                track: Arc::new(TrackLocalStaticRTP::new(
+                    track.codec(),
+                    track.id().to_owned(),
+                    track.stream_id().to_owned(),
+                )),
+                feed: feed.clone(),
+                forwarder: Mutex::new(None),
+            }),
+            None => Arc::clone(track) as Arc<dyn TrackLocal + Send + Sync>,
+        }
+    }
+}
+
+#[async_trait]
+impl TrackLocal for ReplayingTrack {
+    async fn bind(&self, t: &TrackLocalContext) -> Result<RTCRtpCodecParameters, Error> {
+        let feed = self.feed.subscribe();
+        if !feed.has_keyframe() {
+            return self.shared.bind(t).await;
+        }
+        let codec = self.track.bind(t).await?;
+        let track = Arc::clone(&self.track) as Arc<dyn TrackLocalWriter + Send + Sync>;
+        if let Some(forwarder) = self
+            .forwarder
+            .lock()
+            .unwrap()
+            .replace(tokio::spawn(replay(feed, track)))
+        {
+            forwarder.abort();
+        }
+        Ok(codec)
+    }
+
+    async fn unbind(&self, t: &TrackLocalContext) -> Result<(), Error> {
+        let forwarder = self.forwarder.lock().unwrap().take();
+        match forwarder {
+            Some(forwarder) => {
+                forwarder.abort();
+                self.track.unbind(t).await
+            }
+            None => self.shared.unbind(t).await,
+        }
+    }
+
+    fn id(&self) -> &str {
+        self.shared.id()
+    }
+
+    fn stream_id(&self) -> &str {
+        self.shared.stream_id()
+    }
+
+    fn kind(&self) -> RTPCodecType {
+        self.shared.kind()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for ReplayingTrack {
+    fn drop(&mut self) {
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            forwarder.abort();
+        }
+    }
+}
+
+/// Forwards the packets of the feed, starting with its keyframe.
+///
+/// A sender being started binds its track before its interceptors are set up, and until then
+/// the packets written are dropped, so the first packet is written again until it is taken.
+///
+async fn replay(mut feed: FeedSubscription, track: Arc<dyn TrackLocalWriter + Send + Sync>) {
+    let first = match feed.next().await {
+        Some(first) => first,
+        None => return,
+    };
+    let mut attempts = 1;
+    loop {
+        match track.write_rtp(&first).await {
+            Ok(0) if attempts < REPLAY_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::sleep(REPLAY_RETRY_INTERVAL).await;
+            }
+            Ok(0) => {
+                debug!("The sender didn't start to replay a keyframe.");
+                break;
+            }
+            Ok(_) => break,
+            Err(e) => {
+                debug!("Error while replaying a keyframe: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    while let Some(packet) = feed.next().await {
+        if let Err(e) = track.write_rtp(&packet).await {
+            if Error::ErrClosedPipe != e {
+                error!("replaying track write_rtp got error: {}.", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Maps the sequence numbers and timestamps of the current source onto the output stream.
+///
+struct RtpRewriter {
+    clock_rate: u32,
+    /// The sequence number and timestamp last sent, and when.
+    last: Option<(u16, u32, Instant)>,
+    /// The offsets to the current source, which are fixed by its first packet.
+    offsets: Option<(u16, u32)>,
+}
+
+impl RtpRewriter {
+    fn new(clock_rate: u32) -> Self {
+        RtpRewriter {
+            clock_rate,
+            last: None,
+            offsets: None,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.offsets = None;
+    }
+
+    fn rewrite(&mut self, header: &mut Header) {
+        let (seq_offset, ts_offset) = match self.offsets {
+            Some(offsets) => offsets,
+            None => {
+                // The first packet of a new source follows the last one sent,
+                // as far apart in time as they actually are.
+                let offsets = match self.last {
+                    Some((seq, ts, at)) => {
+                        let elapsed = (at.elapsed().as_secs_f64() * self.clock_rate as f64) as u32;
+                        (
+                            seq.wrapping_add(1).wrapping_sub(header.sequence_number),
+                            ts.wrapping_add(elapsed.max(1))
+                                .wrapping_sub(header.timestamp),
+                        )
+                    }
+                    None => (0, 0),
+                };
+                self.offsets = Some(offsets);
+                offsets
+            }
+        };
+
+        header.sequence_number = header.sequence_number.wrapping_add(seq_offset);
+        header.timestamp = header.timestamp.wrapping_add(ts_offset);
+
+        // Packets reordered in the network don't move the output stream back.
+        let is_newer = match self.last {
+            Some((seq, _, _)) => (header.sequence_number.wrapping_sub(seq) as i16) > 0,
+            None => true,
+        };
+        if is_newer {
+            self.last = Some((header.sequence_number, header.timestamp, Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the sequence numbers a binding takes, dropping the first packets
+    /// written as a sender does until it is started.
+    #[derive(Debug, Default)]
+    struct Binding {
+        dropped: Mutex<usize>,
+        sequence_numbers: Mutex<Vec<u16>>,
+    }
+
+    #[async_trait]
+    impl TrackLocalWriter for Binding {
+        async fn write_rtp(&self, p: &Packet) -> Result<usize, Error> {
+            let mut dropped = self.dropped.lock().unwrap();
+            if *dropped > 0 {
+                *dropped -= 1;
+                return Ok(0);
+            }
+            self.sequence_numbers
+                .lock()
+                .unwrap()
+                .push(p.header.sequence_number);
+            Ok(1)
+        }
+
+        async fn write(&self, _b: &[u8]) -> Result<usize, Error> {
+            Ok(0)
+        }
+    }
+
+    fn packet(sequence_number: u16) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn live_packets_follow_replayed_keyframe_on_binding() {
+        let feed = PacketFeed::new(None);
+        let mut subscription = feed.subscribe();
+        subscription.keyframe = Some(Arc::new(vec![packet(10), packet(11), packet(12)]));
+
+        // The packets sent while the keyframe was taken are repeated by the feed.
+        for sequence_number in 11..15 {
+            feed.send(&packet(sequence_number));
+        }
+        drop(feed);
+
+        let binding = Arc::new(Binding {
+            dropped: Mutex::new(3),
+            ..Default::default()
+        });
+        replay(subscription, Arc::clone(&binding) as _).await;
+
+        assert_eq!(
+            *binding.sequence_numbers.lock().unwrap(),
+            vec![10, 11, 12, 13, 14]
+        );
+    }
+}